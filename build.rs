@@ -1,3 +1,123 @@
+use std::env;
+use std::fmt::Write as _;
+use std::fs;
+use std::path::PathBuf;
+
+// External interrupts of the CH32V003 in vector order, named like
+// pac::Interrupt. The vector of the USB EXTI line gets the handler defined by
+// usb_interrupt!, the others the usual handlers (DefaultHandler if missing).
+const FIRST_EXTERNAL: usize = 16;
+const INTERRUPTS: &[&str] = &[
+    "WWDG",
+    "PVD",
+    "FLASH",
+    "RCC",
+    "EXTI7_0",
+    "AWU",
+    "DMA1_CHANNEL1",
+    "DMA1_CHANNEL2",
+    "DMA1_CHANNEL3",
+    "DMA1_CHANNEL4",
+    "DMA1_CHANNEL5",
+    "DMA1_CHANNEL6",
+    "DMA1_CHANNEL7",
+    "ADC",
+    "I2C1_EV",
+    "I2C1_ER",
+    "USART1",
+    "SPI1",
+    "TIM1_BRK",
+    "TIM1_UP",
+    "TIM1_TRG_COM",
+    "TIM1_CC",
+    "TIM2",
+];
+const USB_INTERRUPT: &str = "EXTI7_0";
+const USB_HANDLER: &str = "EXTI7_0_IRQHandler";
+
+fn handler(irq: &str) -> &str {
+    if irq == USB_INTERRUPT {
+        USB_HANDLER
+    } else {
+        irq
+    }
+}
+
+// Included by src/vectors.rs
+fn vector_table() -> String {
+    let mut s = String::new();
+    s.push_str("extern \"C\" {\n");
+    for irq in INTERRUPTS {
+        writeln!(s, "    fn {}();", handler(irq)).unwrap();
+    }
+    s.push_str("}\n\n");
+    s.push_str("#[link_section = \".vector_table.external_interrupts_usb\"]\n#[no_mangle]\n");
+    writeln!(s, "pub static __EXTERNAL_INTERRUPTS_USB: [Vector; {}] = [", INTERRUPTS.len()).unwrap();
+    for irq in INTERRUPTS {
+        writeln!(s, "    Vector {{ _handler: {} }},", handler(irq)).unwrap();
+    }
+    s.push_str("];\n\n");
+    // Catches a renumbered or renamed interrupt in a new ch32-hal
+    s.push_str("const _: () = {\n");
+    for (i, irq) in INTERRUPTS.iter().enumerate() {
+        writeln!(s, "    assert!(Interrupt::{irq} as usize == {});", FIRST_EXTERNAL + i).unwrap();
+    }
+    s.push_str("};\n");
+    s
+}
+
+// Included by link_usb.x
+fn device_script() -> String {
+    let mut s = String::new();
+    for irq in INTERRUPTS.iter().filter(|irq| **irq != USB_INTERRUPT) {
+        writeln!(s, "PROVIDE({irq} = DefaultHandler);").unwrap();
+    }
+    s
+}
+
 fn main() {
-    println!("cargo:rustc-link-arg-bins=-Tlink_usb.x");
+    let out = PathBuf::from(env::var_os("OUT_DIR").unwrap());
+
+    let bootloader = env::var_os("CARGO_FEATURE_LAYOUT_DFU_BOOTLOADER").is_some();
+    let app = env::var_os("CARGO_FEATURE_LAYOUT_DFU_APP").is_some();
+    // The stored HSI trim takes the last page of flash, the bootloader ends
+    // before it anyway
+    let trim = env::var_os("CARGO_FEATURE_PERSIST_TRIM").is_some();
+    let memory = match (bootloader, app, trim) {
+        (false, false, false) => "memory.x",
+        (false, false, true) => "memory_trim.x",
+        (true, false, _) => "memory_bootloader.x",
+        (false, true, false) => "memory_app.x",
+        (false, true, true) => "memory_app_trim.x",
+        (true, true, _) => panic!("layout-dfu-bootloader and layout-dfu-app are exclusive"),
+    };
+
+    // Dependents find the scripts on the search path, like cortex-m-rt's link.x
+    fs::copy(memory, out.join("memory.x")).unwrap();
+    fs::copy("link_usb.x", out.join("link_usb.x")).unwrap();
+    fs::write(out.join("device_usb.x"), device_script()).unwrap();
+    fs::write(out.join("vectors.rs"), vector_table()).unwrap();
+
+    // Crystal frequency for feature hse-24mhz, src/clock.rs rejects anything
+    // which doesn't give 48 MHz through the PLL
+    let hse_hz = env::var("RV003USB_HSE_HZ").unwrap_or_else(|_| "24000000".into());
+    if hse_hz.parse::<u32>().is_err() {
+        panic!("RV003USB_HSE_HZ has to be the crystal frequency in Hz, not {hse_hz:?}");
+    }
+    println!("cargo:rustc-env=RV003USB_HSE_HZ={hse_hz}");
+    println!("cargo:rerun-if-env-changed=RV003USB_HSE_HZ");
+    println!("cargo:rustc-link-search={}", out.display());
+    println!("cargo:rustc-link-arg-examples=-Tlink_usb.x");
+
+    for file in [
+        "build.rs",
+        "memory.x",
+        "memory_trim.x",
+        "memory_app.x",
+        "memory_app_trim.x",
+        "memory_bootloader.x",
+        "link_usb.x",
+    ] {
+        println!("cargo:rerun-if-changed={file}");
+    }
 }
@@ -0,0 +1,31 @@
+// Absolute pointer moved by raw HID command 0x06 (feature demo-abs-pointer)
+use core::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+pub use rv003usb::hid::{AbsolutePointerReport, ABS_POINTER_DESC_LEN};
+use rv003usb::usb::UsbEndpoint;
+
+use crate::device;
+
+// x | y << 16 in pixels of a full HD screen, set by the raw HID command
+static ABS_POINTER_POS: AtomicU32 = AtomicU32::new(0);
+static ABS_POINTER_MOVED: AtomicBool = AtomicBool::new(false);
+
+pub fn abs_pointer_in(_e: *mut UsbEndpoint, usbif: &mut device::Usb, sendtok: u32) {
+    // Absolute pointer (5 bytes), only sent when it moved so the host
+    // mouse isn't pinned to it
+    if !ABS_POINTER_MOVED.load(Ordering::Relaxed) {
+        usbif.usb_send_nak();
+        return;
+    }
+    ABS_POINTER_MOVED.store(false, Ordering::Relaxed);
+    let pos = ABS_POINTER_POS.load(Ordering::Relaxed);
+    let report = AbsolutePointerReport::from_screen(0, pos as u16, (pos >> 16) as u16, 1920, 1080).to_bytes();
+    unsafe { usbif.usb_send_data(report.as_ptr(), 5, 0, sendtok) };
+}
+
+// 0x06 <x:u16> <y:u16>
+pub fn move_to(cmd: &[u8]) {
+    let x = u16::from_le_bytes([cmd[1], cmd[2]]) as u32;
+    let y = u16::from_le_bytes([cmd[3], cmd[4]]) as u32;
+    ABS_POINTER_POS.store(x | y << 16, Ordering::Relaxed);
+    ABS_POINTER_MOVED.store(true, Ordering::Relaxed);
+}
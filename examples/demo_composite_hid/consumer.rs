@@ -0,0 +1,26 @@
+// Consumer control tapping the keys of raw HID command 0x04 (feature
+// demo-consumer)
+use core::sync::atomic::{AtomicU16, Ordering};
+pub use rv003usb::hid::{ConsumerReport, CONSUMER_DESC_LEN};
+use rv003usb::usb::UsbEndpoint;
+
+use crate::device;
+
+// Set by the raw HID command, sent by the IN handler
+static CONSUMER_TAP: AtomicU16 = AtomicU16::new(0);
+
+pub fn consumer_in(_e: *mut UsbEndpoint, usbif: &mut device::Usb, sendtok: u32) {
+    // Consumer control (2 bytes), a tap is pressed for one report and
+    // released with the next
+    let usage = CONSUMER_TAP.load(Ordering::Relaxed);
+    if usage != 0 {
+        CONSUMER_TAP.store(0, Ordering::Relaxed);
+    }
+    let report = usage.to_le_bytes();
+    unsafe { usbif.usb_send_data(report.as_ptr(), 2, 0, sendtok) };
+}
+
+// 0x04 <usage:u16>
+pub fn tap(cmd: &[u8]) {
+    CONSUMER_TAP.store(u16::from_le_bytes([cmd[1], cmd[2]]), Ordering::Relaxed);
+}
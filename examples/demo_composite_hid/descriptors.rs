@@ -0,0 +1,49 @@
+use rv003usb::descriptors::StringDescriptor;
+use utf16_lit::utf16;
+
+#[link_section = ".rodata"]
+static DEVICE_DESCRIPTOR: [u8; 18] = [
+    18, // Length
+    1,  // Type (Device)
+    0x10, 0x01, // Spec
+    0x0,  // Device Class
+    0x0,  // Device Subclass
+    0x0,  // Device Protocol  (000 = use config descriptor)
+    0x08, // Max packet size for EP0 (This has to be 8 because of the USB
+    // Low-Speed Standard)
+    0x09, 0x12, // ID Vendor
+    0x03, 0xd0, // ID Product
+    0x02, 0x00, // ID Rev
+    1,    // Manufacturer string
+    2,    // Product string
+    3,    // Serial string
+    1,    // Max number of configurations
+];
+
+// Define your strings manually as UTF-16 arrays
+static STR_LANG: StringDescriptor<1> = StringDescriptor::new(&[0x0409]); // English
+static STR_MANUF: StringDescriptor<6> = StringDescriptor::new(&utf16!("CNLohr"));
+static STR_PROD: StringDescriptor<8> = StringDescriptor::new(&utf16!("RV003USB"));
+static STR_SERIAL: StringDescriptor<3> = StringDescriptor::new(&utf16!("000"));
+static STR_ERR: StringDescriptor<3> = StringDescriptor::new(&utf16!("ERR"));
+
+pub fn get_descriptor_info(w_value: u32) -> (*const u8, u16) {
+    let slice: &[u8] = match w_value {
+        // The configuration and HID report descriptors are generated by
+        // usb_composite! in main.rs
+        0x00000100 => &DEVICE_DESCRIPTOR,
+        0x00000300 => STR_LANG.as_bytes(),
+        0x04090301 => STR_MANUF.as_bytes(),
+        0x04090302 => {
+            if !crate::device::descriptors_valid() {
+                STR_ERR.as_bytes()
+            } else {
+                STR_PROD.as_bytes()
+            }
+        }
+        0x04090303 => STR_SERIAL.as_bytes(),
+        _ => &[], // Return empty slice for null/not found
+    };
+
+    (slice.as_ptr(), slice.len() as u16)
+}
@@ -0,0 +1,37 @@
+// Gamepad in the state set by raw HID command 0x05 (feature demo-gamepad)
+use core::sync::atomic::{AtomicI8, AtomicU16, AtomicU8, Ordering};
+use rv003usb::gamepad::Hat;
+use rv003usb::gamepad_report;
+use rv003usb::usb::UsbEndpoint;
+
+use crate::device;
+
+// 12 buttons, hat switch and X/Y stick, 5 bytes
+gamepad_report!(pub struct GamepadReport, GAMEPAD_DESC_LEN { buttons: u16 = 12, axes: 2 });
+
+// Set by the raw HID command, sent by the IN handler
+static GAMEPAD_BUTTONS: AtomicU16 = AtomicU16::new(0);
+static GAMEPAD_HAT: AtomicU8 = AtomicU8::new(8);
+static GAMEPAD_AXES: [AtomicI8; 2] = [AtomicI8::new(0), AtomicI8::new(0)];
+
+pub fn gamepad_in(_e: *mut UsbEndpoint, usbif: &mut device::Usb, sendtok: u32) {
+    // Gamepad (5 bytes)
+    let report = GamepadReport {
+        buttons: GAMEPAD_BUTTONS.load(Ordering::Relaxed),
+        hat: Hat::from_value(GAMEPAD_HAT.load(Ordering::Relaxed)),
+        axes: [
+            GAMEPAD_AXES[0].load(Ordering::Relaxed),
+            GAMEPAD_AXES[1].load(Ordering::Relaxed),
+        ],
+    }
+    .to_bytes();
+    unsafe { usbif.usb_send_data(report.as_ptr(), report.len() as u32, 0, sendtok) };
+}
+
+// 0x05 <buttons:u16> <hat> <x:i8> <y:i8>
+pub fn set_state(cmd: &[u8]) {
+    GAMEPAD_BUTTONS.store(u16::from_le_bytes([cmd[1], cmd[2]]), Ordering::Relaxed);
+    GAMEPAD_HAT.store(cmd[3], Ordering::Relaxed);
+    GAMEPAD_AXES[0].store(cmd[4] as i8, Ordering::Relaxed);
+    GAMEPAD_AXES[1].store(cmd[5] as i8, Ordering::Relaxed);
+}
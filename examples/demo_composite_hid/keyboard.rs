@@ -0,0 +1,36 @@
+// Boot keyboard typing the text of raw HID command 0x03, the demo function
+// unless one of the demo-* features picks another
+pub use rv003usb::hid::{KeyboardReport, KBD_DESC_LEN};
+use rv003usb::raw_hid::{self, Response};
+use rv003usb::typing::{Layout, Typist};
+use rv003usb::usb::UsbEndpoint;
+
+use crate::device;
+
+static TYPIST: Typist = Typist::new(Layout::Us);
+static mut TSAJOYSTICK_KEYBOARD: [u8; 8] = [0x00; 8];
+
+pub fn keyboard_in(_e: *mut UsbEndpoint, usbif: &mut device::Usb, sendtok: u32) {
+    // Keyboard (8 bytes)
+    unsafe {
+        usbif.usb_send_data(TSAJOYSTICK_KEYBOARD.as_ptr(), 8, 0, sendtok);
+
+        // Prepare the next report, all keys released unless
+        // there's something to type.
+        if !TYPIST.next_report(&mut TSAJOYSTICK_KEYBOARD) {
+            TSAJOYSTICK_KEYBOARD = [0; 8];
+        }
+    }
+}
+
+// 0x03 <text>, answered with the number of characters queued
+pub fn type_text(text: &[u8]) -> Response {
+    let len = text.iter().position(|&c| c == 0).unwrap_or(text.len());
+    let queued = core::str::from_utf8(&text[..len])
+        .map(|text| TYPIST.type_str(text))
+        .unwrap_or(0);
+    let mut report = [0; raw_hid::RAW_HID_REPORT_LEN];
+    report[0] = 0x03;
+    report[1] = queued as u8;
+    Response::Report(report)
+}
@@ -0,0 +1,203 @@
+#![no_std]
+#![no_main]
+#![feature(type_alias_impl_trait)]
+#![feature(impl_trait_in_assoc_type)]
+
+use hal::delay::Delay;
+use hal::gpio::{Level, Output};
+use hal::peripherals::{PC2, PC3};
+use {ch32_hal as hal, panic_halt as _};
+use rv003usb::dfu::DfuRuntime;
+#[cfg(feature = "demo-nkro")]
+use rv003usb::nkro::{NkroKeyboard, NkroKeyboardReport, NKRO_DESC_LEN};
+use rv003usb::raw_hid::{self, RawHid, RawHidReport, Response, RAW_HID_DESC_LEN};
+use rv003usb::usb_composite;
+mod descriptors;
+
+// A low speed device only has two endpoints besides endpoint 0, the raw HID
+// interface takes one and the keyboard or the function picked with one of the
+// demo-* features the other.
+#[cfg(feature = "demo-abs-pointer")]
+mod abs_pointer;
+#[cfg(feature = "demo-consumer")]
+mod consumer;
+#[cfg(feature = "demo-gamepad")]
+mod gamepad;
+#[cfg(not(any(
+    feature = "demo-mouse",
+    feature = "demo-consumer",
+    feature = "demo-gamepad",
+    feature = "demo-nkro",
+    feature = "demo-abs-pointer",
+)))]
+mod keyboard;
+#[cfg(feature = "demo-mouse")]
+mod mouse;
+
+usb_composite! {
+    // D+ on PC3, D- on PC2, the pull-up is passed to new()
+    pub mod device for UsbIf<PC3, PC2> {
+        max_power: 200,
+        descriptors: descriptors::get_descriptor_info,
+        functions: {
+            #[cfg(not(any(
+                feature = "demo-mouse",
+                feature = "demo-consumer",
+                feature = "demo-gamepad",
+                feature = "demo-nkro",
+                feature = "demo-abs-pointer",
+            )))]
+            keyboard: hid_keyboard(keyboard::KeyboardReport, keyboard::KBD_DESC_LEN) {
+                in: keyboard::keyboard_in,
+            },
+            #[cfg(feature = "demo-mouse")]
+            mouse: hid_mouse(mouse::MouseReport, mouse::MOUSE_DESC_LEN) {
+                in: mouse::mouse_in,
+            },
+            #[cfg(feature = "demo-consumer")]
+            consumer: hid(consumer::ConsumerReport, consumer::CONSUMER_DESC_LEN) {
+                in: consumer::consumer_in,
+            },
+            #[cfg(feature = "demo-gamepad")]
+            gamepad: hid(gamepad::GamepadReport, gamepad::GAMEPAD_DESC_LEN) {
+                in: gamepad::gamepad_in,
+            },
+            #[cfg(feature = "demo-nkro")]
+            nkro: hid_keyboard(NkroKeyboardReport, NKRO_DESC_LEN) {
+                // 16 bytes in two packets, 8 in boot protocol
+                in: |e, usbif, sendtok| NKRO.handle_in(e, usbif, sendtok),
+                setup: |e, request, wvi, w_length| NKRO.handle_setup(e, request, wvi, w_length),
+                reset: || NKRO.reset(),
+            },
+            #[cfg(feature = "demo-abs-pointer")]
+            abs_pointer: hid(abs_pointer::AbsolutePointerReport, abs_pointer::ABS_POINTER_DESC_LEN) {
+                in: abs_pointer::abs_pointer_in,
+            },
+            // Commands are feature reports, there is no room for an OUT endpoint
+            raw_hid: hid(RawHidReport, RAW_HID_DESC_LEN) {
+                in: |_e, usbif, sendtok| RAW_HID.handle_in(usbif, sendtok),
+                setup: |e, request, wvi, w_length| RAW_HID.handle_setup(e, request, wvi, w_length),
+                control_out: |e, data| RAW_HID.handle_feature_data(e, data),
+                reset: || RAW_HID.reset(),
+            },
+            // dfu-util -e reboots into the bootloader
+            dfu: dfu_runtime() {
+                setup: |e, request, wvi, w_length| DFU.handle_setup(e, request, wvi, w_length),
+            },
+        }
+    }
+}
+
+static RAW_HID: RawHid = RawHid::new(device::Interface::raw_hid.number());
+#[cfg(feature = "demo-nkro")]
+static NKRO: NkroKeyboard = NkroKeyboard::new(device::Interface::nkro.number());
+static DFU: DfuRuntime = DfuRuntime::new(device::Interface::dfu.number());
+
+#[qingke_rt::entry]
+fn main() -> ! {
+    // hal::debug::SDIPrint::enable();
+    let mut config = hal::Config::default();
+    config.rcc = rv003usb::clock::rcc_config();
+    let p = hal::init(config);
+
+    let mut delay = Delay;
+
+    let mut led1 = Output::new(p.PA1, Level::Low, Default::default());
+    let mut led2 = Output::new(p.PC0, Level::Low, Default::default());
+
+    // USB setup
+    let usb = device::new(p.PC3, p.PC2, p.PC5);
+    // DFU_DETACH goes to our bootloader instead of the factory one
+    #[cfg(feature = "layout-dfu-app")]
+    usb.set_reboot_target(rv003usb::usb::RebootTarget::Custom(
+        rv003usb::dfu::reboot_to_dfu_bootloader,
+    ));
+
+    let mut usb = usb.start();
+    usb.connect();
+    // USB setup done
+
+    let mut ticks = 0;
+    #[cfg(feature = "persist-trim")]
+    let mut trim_stored = false;
+    loop {
+        // Suspended: LEDs off and Standby until the host resumes the bus
+        if usb.poll() {
+            led1.set_low();
+            led2.set_low();
+            // Keep the settled trim for the next start, programming flash
+            // stalls the USB interrupt which doesn't matter while suspended
+            #[cfg(feature = "persist-trim")]
+            if !trim_stored {
+                trim_stored = usb.store_trim() == Ok(true);
+            }
+            usb.sleep();
+            continue;
+        }
+        RAW_HID.poll(|cmd| handle_raw_hid(cmd, &mut led2));
+        ticks += 1;
+        if ticks == 1000 {
+            ticks = 0;
+            led1.toggle();
+        }
+        delay.delay_ms(1);
+        // hal::println!("toggle!");
+        // let val = hal::pac::SYSTICK.cnt().read();
+        // hal::println!("systick: {}", val)
+    }
+}
+
+// Example command set for the raw HID interface, sent as feature reports and
+// answered with the next feature report read
+// 0x01: echo the command back
+// 0x02 <on>: set led2
+// 0x03 <text>: type up to 7 characters of text on the keyboard
+// 0x04 <usage:u16>: tap a consumer control key, e.g. 0xe2 0x00 for mute
+// 0x05 <buttons:u16> <hat> <x:i8> <y:i8>: set the gamepad state, hat 0-7
+//      clockwise from up, anything else is centered
+// 0x06 <x:u16> <y:u16>: move the absolute pointer on a 1920x1080 screen
+// 16 bit values are little endian, every command is answered with its
+// command byte. 0x03-0x06 need the function to be built in.
+fn handle_raw_hid(cmd: &[u8], led: &mut Output) -> Response {
+    match cmd[0] {
+        0x01 => {
+            let mut report = [0; raw_hid::RAW_HID_REPORT_LEN];
+            report.copy_from_slice(cmd);
+            Response::Report(report)
+        }
+        0x02 => {
+            led.set_level(if cmd[1] != 0 { Level::High } else { Level::Low });
+            ack(0x02)
+        }
+        #[cfg(not(any(
+            feature = "demo-mouse",
+            feature = "demo-consumer",
+            feature = "demo-gamepad",
+            feature = "demo-nkro",
+            feature = "demo-abs-pointer",
+        )))]
+        0x03 => keyboard::type_text(&cmd[1..]),
+        #[cfg(feature = "demo-consumer")]
+        0x04 => {
+            consumer::tap(cmd);
+            ack(0x04)
+        }
+        #[cfg(feature = "demo-gamepad")]
+        0x05 => {
+            gamepad::set_state(cmd);
+            ack(0x05)
+        }
+        #[cfg(feature = "demo-abs-pointer")]
+        0x06 => {
+            abs_pointer::move_to(cmd);
+            ack(0x06)
+        }
+        _ => Response::None,
+    }
+}
+
+fn ack(command: u8) -> Response {
+    let mut report = [0; raw_hid::RAW_HID_REPORT_LEN];
+    report[0] = command;
+    Response::Report(report)
+}
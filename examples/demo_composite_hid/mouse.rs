@@ -0,0 +1,42 @@
+// Mouse moving in a square (feature demo-mouse)
+pub use rv003usb::hid::{MouseReport, MOUSE_DESC_LEN};
+use rv003usb::usb::UsbEndpoint;
+
+use crate::device;
+
+static mut I_MOUSE: i32 = 0;
+static mut TSAJOYSTICK_MOUSE: [u8; 4] = [0x00, 0x00, 0x00, 0x00];
+
+pub fn mouse_in(_e: *mut UsbEndpoint, usbif: &mut device::Usb, sendtok: u32) {
+    // Mouse (4 bytes)
+    unsafe {
+        I_MOUSE += 1;
+        let mut mode = I_MOUSE >> 2;
+
+        TSAJOYSTICK_MOUSE[1] = 0;
+        TSAJOYSTICK_MOUSE[2] = 0;
+        // Move the mouse right, down, left and up in a square.
+        if I_MOUSE & 0b11 == 0 {
+            match mode & 3 {
+                0 => {
+                    TSAJOYSTICK_MOUSE[1] = 1;
+                    TSAJOYSTICK_MOUSE[2] = 0;
+                }
+                1 => {
+                    TSAJOYSTICK_MOUSE[1] = 0;
+                    TSAJOYSTICK_MOUSE[2] = 1;
+                }
+                2 => {
+                    TSAJOYSTICK_MOUSE[1] = -1i8 as u8; // Need to cast to u8 for the array
+                    TSAJOYSTICK_MOUSE[2] = 0;
+                }
+                3 => {
+                    TSAJOYSTICK_MOUSE[1] = 0;
+                    TSAJOYSTICK_MOUSE[2] = -1i8 as u8; // Need to cast to u8 for the array
+                }
+                _ => {}
+            }
+        }
+        usbif.usb_send_data(TSAJOYSTICK_MOUSE.as_ptr(), 4, 0, sendtok);
+    }
+}
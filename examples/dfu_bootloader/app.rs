@@ -0,0 +1,68 @@
+// Application area and the page describing it, see memory_bootloader.x and
+// memory_app.x (memory_app_trim.x)
+use rv003usb::flash::{self, FLASH_SIZE, PAGE_SIZE, TRIM_PAGE_OFFSET};
+
+/// Start of the application, relative to the start of flash
+pub const APP_OFFSET: u32 = 0x1800;
+/// End of the application area, with feature persist-trim the last page
+/// holds the stored HSI trim (like in memory_app_trim.x)
+const APP_END: u32 = if cfg!(feature = "persist-trim") { TRIM_PAGE_OFFSET } else { FLASH_SIZE };
+pub const APP_SIZE: u32 = APP_END - APP_OFFSET;
+/// Last page of the bootloader area, written after a complete download
+pub const INFO_OFFSET: u32 = APP_OFFSET - PAGE_SIZE as u32;
+
+const INFO_MAGIC: u32 = 0x4150_5031; // "APP1"
+
+pub const CRC_INIT: u32 = 0xffff_ffff;
+
+/// CRC-32 (IEEE) without the final inversion, start with CRC_INIT
+pub fn crc32_update(mut crc: u32, data: &[u8]) -> u32 {
+    for &byte in data {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            let mask = (crc & 1).wrapping_neg();
+            crc = (crc >> 1) ^ (0xedb8_8320 & mask);
+        }
+    }
+    crc
+}
+
+/// CRC-32 (IEEE) of data, with the final inversion
+pub fn crc32(data: &[u8]) -> u32 {
+    !crc32_update(CRC_INIT, data)
+}
+
+/// Contents of the info page
+pub fn info_page(length: u32, crc: u32) -> [u8; 12] {
+    let mut page = [0; 12];
+    page[0..4].copy_from_slice(&INFO_MAGIC.to_le_bytes());
+    page[4..8].copy_from_slice(&length.to_le_bytes());
+    page[8..12].copy_from_slice(&crc.to_le_bytes());
+    page
+}
+
+/// Length and CRC of the application, None if the last download didn't
+/// complete
+pub fn info() -> Option<(u32, u32)> {
+    let page = flash::read_page(INFO_OFFSET);
+    let word = |i: usize| u32::from_le_bytes([page[i], page[i + 1], page[i + 2], page[i + 3]]);
+    let (magic, length, crc) = (word(0), word(4), word(8));
+    if magic != INFO_MAGIC || length == 0 || length > APP_SIZE {
+        return None;
+    }
+    Some((length, crc))
+}
+
+/// Checks the application against the CRC in the info page
+pub fn valid() -> bool {
+    match info() {
+        Some((length, crc)) => crc32(flash::read(APP_OFFSET, length as usize)) == crc,
+        None => false,
+    }
+}
+
+/// Jumps to the reset entry (.init) of the application. Only call this before
+/// any peripheral is set up, the application starts as if from reset.
+pub fn start() -> ! {
+    unsafe { core::arch::asm!("jr {0}", in(reg) APP_OFFSET, options(noreturn)) }
+}
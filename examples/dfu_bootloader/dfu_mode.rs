@@ -0,0 +1,275 @@
+// USB DFU 1.1 DFU mode interface
+//
+// Requests are answered in the interrupt, erasing and programming flash is
+// done by poll() in the main loop. While a block is being written the host is
+// told to come back after POLL_TIMEOUT_MS (dfuDNBUSY / dfuMANIFEST).
+//
+// Every block is one flash page. The first block invalidates the info page,
+// the manifestation checks the CRC of the written application against the
+// received data and writes the info page.
+use core::cell::UnsafeCell;
+use core::sync::atomic::{AtomicBool, AtomicU16, AtomicU32, AtomicU8, Ordering};
+
+use rv003usb::usb::UsbEndpoint;
+
+use crate::app::{self, APP_OFFSET, APP_SIZE, CRC_INIT, INFO_OFFSET};
+use rv003usb::flash::{self, PAGE_SIZE};
+
+// bState
+const DFU_IDLE: u8 = 2;
+const DNLOAD_SYNC: u8 = 3;
+const DNBUSY: u8 = 4;
+const DNLOAD_IDLE: u8 = 5;
+const MANIFEST_SYNC: u8 = 6;
+const MANIFEST: u8 = 7;
+const UPLOAD_IDLE: u8 = 9;
+const DFU_ERROR: u8 = 10;
+
+// bStatus
+const OK: u8 = 0x00;
+const ERR_WRITE: u8 = 0x03;
+const ERR_ERASE: u8 = 0x04;
+const ERR_VERIFY: u8 = 0x07;
+const ERR_ADDRESS: u8 = 0x08;
+const ERR_NOTDONE: u8 = 0x09;
+const ERR_STALLEDPKT: u8 = 0x0f;
+
+/// Erasing and programming a page takes a few ms
+const POLL_TIMEOUT_MS: u8 = 10;
+
+pub struct DfuMode {
+    interface: u16,
+    state: AtomicU8,
+    status: AtomicU8,
+    // Block or manifestation handed to the main loop
+    pending: AtomicBool,
+    // The application was written successfully
+    manifested: AtomicBool,
+    block: UnsafeCell<[u8; PAGE_SIZE]>,
+    block_num: AtomicU16,
+    block_len: AtomicU8,
+    received: AtomicU8,
+    // Bytes written so far and their CRC, only used by the main loop
+    length: AtomicU32,
+    crc: AtomicU32,
+    status_response: UnsafeCell<[u8; 6]>,
+}
+
+// The block buffer is handed over with the pending flag, the status response
+// is only used by the interrupt.
+unsafe impl Sync for DfuMode {}
+
+impl DfuMode {
+    pub const fn new(interface: u16) -> Self {
+        Self {
+            interface,
+            state: AtomicU8::new(DFU_IDLE),
+            status: AtomicU8::new(OK),
+            pending: AtomicBool::new(false),
+            manifested: AtomicBool::new(false),
+            block: UnsafeCell::new([0; PAGE_SIZE]),
+            block_num: AtomicU16::new(0),
+            block_len: AtomicU8::new(0),
+            received: AtomicU8::new(0),
+            length: AtomicU32::new(0),
+            crc: AtomicU32::new(CRC_INIT),
+            status_response: UnsafeCell::new([0; 6]),
+        }
+    }
+
+    /// True once a download completed, the application can be started
+    pub fn manifested(&self) -> bool {
+        self.manifested.load(Ordering::Relaxed)
+    }
+
+    fn error(&self, status: u8) {
+        self.status.store(status, Ordering::Relaxed);
+        self.state.store(DFU_ERROR, Ordering::Relaxed);
+    }
+
+    /// Call from the user setup handler. Returns true if the request was for
+    /// this interface.
+    pub fn handle_setup(&self, e: &mut UsbEndpoint, request: u16, wvi: u32, w_length: u16) -> bool {
+        let interface = (wvi >> 16) as u16;
+        if interface != self.interface {
+            return false;
+        }
+        let state = self.state.load(Ordering::Relaxed);
+        let block_num = wvi as u16;
+        match request {
+            0x0121 => {
+                // DFU_DNLOAD
+                if state != DFU_IDLE && state != DNLOAD_IDLE {
+                    self.error(ERR_STALLEDPKT);
+                } else if w_length == 0 {
+                    if state == DNLOAD_IDLE {
+                        self.state.store(MANIFEST_SYNC, Ordering::Relaxed);
+                        self.pending.store(true, Ordering::Release);
+                    } else {
+                        self.error(ERR_NOTDONE);
+                    }
+                } else if w_length as usize > PAGE_SIZE {
+                    self.error(ERR_ADDRESS);
+                } else {
+                    // Data follows on endpoint 0
+                    self.block_num.store(block_num, Ordering::Relaxed);
+                    self.block_len.store(w_length as u8, Ordering::Relaxed);
+                    self.received.store(0, Ordering::Relaxed);
+                    self.state.store(DNLOAD_SYNC, Ordering::Relaxed);
+                    e.set_max_len(w_length as u32);
+                }
+            }
+            0x02a1 => {
+                // DFU_UPLOAD, the application as described by the info page or
+                // the whole area
+                if state != DFU_IDLE && state != UPLOAD_IDLE {
+                    self.error(ERR_STALLEDPKT);
+                    return true;
+                }
+                let total = app::info().map_or(APP_SIZE, |(length, _)| length);
+                let offset = block_num as u32 * PAGE_SIZE as u32;
+                let len = (w_length as u32).min(total.saturating_sub(offset));
+                let data = flash::read(APP_OFFSET + offset.min(total), len as usize);
+                e.set_in_data(data.as_ptr(), len);
+                // A short block ends the upload
+                let next = if len < w_length as u32 { DFU_IDLE } else { UPLOAD_IDLE };
+                self.state.store(next, Ordering::Relaxed);
+            }
+            0x03a1 => {
+                // DFU_GETSTATUS, also moves the state machine on
+                let mut poll_timeout = 0;
+                let ok = self.status.load(Ordering::Relaxed) == OK;
+                let next = match state {
+                    DNLOAD_SYNC | DNBUSY | MANIFEST_SYNC | MANIFEST
+                        if self.pending.load(Ordering::Acquire) =>
+                    {
+                        poll_timeout = POLL_TIMEOUT_MS;
+                        if state == DNLOAD_SYNC || state == DNBUSY {
+                            DNBUSY
+                        } else {
+                            MANIFEST
+                        }
+                    }
+                    DNLOAD_SYNC | DNBUSY if ok => DNLOAD_IDLE,
+                    MANIFEST_SYNC | MANIFEST if ok => {
+                        self.manifested.store(true, Ordering::Relaxed);
+                        DFU_IDLE
+                    }
+                    DNLOAD_SYNC | DNBUSY | MANIFEST_SYNC | MANIFEST => DFU_ERROR,
+                    _ => state,
+                };
+                self.state.store(next, Ordering::Relaxed);
+                let response = unsafe { &mut *self.status_response.get() };
+                *response = [self.status.load(Ordering::Relaxed), poll_timeout, 0, 0, next, 0];
+                e.set_in_data(response.as_ptr(), (w_length as u32).min(6));
+            }
+            0x0421 => {
+                // DFU_CLRSTATUS
+                if state == DFU_ERROR {
+                    self.status.store(OK, Ordering::Relaxed);
+                    self.state.store(DFU_IDLE, Ordering::Relaxed);
+                }
+            }
+            0x05a1 => {
+                // DFU_GETSTATE
+                e.set_in_data(self.state.as_ptr(), (w_length as u32).min(1));
+            }
+            0x0621 => {
+                // DFU_ABORT, a block being written is finished first
+                if !self.pending.load(Ordering::Acquire) && state != DFU_ERROR {
+                    self.state.store(DFU_IDLE, Ordering::Relaxed);
+                }
+            }
+            _ => return false,
+        }
+        true
+    }
+
+    /// Call on a bus reset, an unfinished download is abandoned (the next one
+    /// starts over with block 0). A block being written is finished first.
+    pub fn reset(&self) {
+        if !self.pending.load(Ordering::Acquire) {
+            self.status.store(OK, Ordering::Relaxed);
+            self.state.store(DFU_IDLE, Ordering::Relaxed);
+        }
+    }
+
+    /// Call from the user data handler for data on endpoint 0
+    pub fn handle_download_data(&self, e: &mut UsbEndpoint, data: &[u8]) {
+        // Every packet counts, also ignored ones, so the status stage is a
+        // zero length packet
+        e.set_count(e.count() + 1);
+        if self.state.load(Ordering::Relaxed) != DNLOAD_SYNC || self.pending.load(Ordering::Acquire) {
+            return;
+        }
+        let offset = self.received.load(Ordering::Relaxed) as usize;
+        let block_len = self.block_len.load(Ordering::Relaxed) as usize;
+        let len = data.len().min(block_len - offset);
+        let block = unsafe { &mut *self.block.get() };
+        block[offset..offset + len].copy_from_slice(&data[..len]);
+        self.received.store((offset + len) as u8, Ordering::Relaxed);
+        if offset + len == block_len {
+            self.pending.store(true, Ordering::Release);
+        }
+    }
+
+    /// Writes a received block or finishes the download, call this regularly
+    /// from the main loop.
+    pub fn poll(&self) {
+        if !self.pending.load(Ordering::Acquire) {
+            return;
+        }
+        let state = self.state.load(Ordering::Relaxed);
+        let status = if state == MANIFEST_SYNC || state == MANIFEST {
+            self.manifest()
+        } else {
+            self.write_block()
+        };
+        self.status.store(status, Ordering::Relaxed);
+        self.pending.store(false, Ordering::Release);
+    }
+
+    fn write_block(&self) -> u8 {
+        let block_num = self.block_num.load(Ordering::Relaxed) as u32;
+        let block_len = self.block_len.load(Ordering::Relaxed) as usize;
+        let data = unsafe { &(*self.block.get())[..block_len] };
+        if block_num == 0 {
+            // New download, the old application is invalid from here on
+            if flash::write_page(INFO_OFFSET, &[]).is_err() {
+                return ERR_ERASE;
+            }
+            self.length.store(0, Ordering::Relaxed);
+            self.crc.store(CRC_INIT, Ordering::Relaxed);
+        }
+        // Blocks have to be consecutive and all but the last one full
+        let length = self.length.load(Ordering::Relaxed);
+        if block_num * PAGE_SIZE as u32 != length || length + block_len as u32 > APP_SIZE {
+            return ERR_ADDRESS;
+        }
+        match flash::write_page(APP_OFFSET + length, data) {
+            Ok(()) => {}
+            Err(flash::Error::Protected) => return ERR_WRITE,
+            Err(flash::Error::Verify) => return ERR_VERIFY,
+        }
+        self.length.store(length + block_len as u32, Ordering::Relaxed);
+        let crc = self.crc.load(Ordering::Relaxed);
+        self.crc.store(app::crc32_update(crc, data), Ordering::Relaxed);
+        OK
+    }
+
+    fn manifest(&self) -> u8 {
+        let length = self.length.load(Ordering::Relaxed);
+        let crc = !self.crc.load(Ordering::Relaxed);
+        if length == 0 {
+            return ERR_NOTDONE;
+        }
+        let written = flash::read(APP_OFFSET, length as usize);
+        if app::crc32(written) != crc {
+            return ERR_VERIFY;
+        }
+        if flash::write_page(INFO_OFFSET, &app::info_page(length, crc)).is_err() {
+            return ERR_WRITE;
+        }
+        OK
+    }
+}
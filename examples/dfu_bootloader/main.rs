@@ -0,0 +1,76 @@
+//! DFU bootloader, build with
+//! `cargo build --release --example dfu_bootloader --features layout-dfu-bootloader`
+//! and the application with `--features layout-dfu-app`.
+//!
+//! Occupies the first 6K of flash, the application is linked behind it (see
+//! memory_bootloader.x and memory_app.x). After a reset the application is
+//! started right away if its CRC matches and it didn't ask for the bootloader
+//! with `rv003usb::dfu::reboot_to_dfu_bootloader`. Otherwise the device
+//! enumerates in DFU mode:
+//!
+//! ```text
+//! dfu-util -d 1209:d003 -D app.bin
+//! ```
+//!
+//! and restarts into the new application shortly after the download.
+#![no_std]
+#![no_main]
+
+use hal::delay::Delay;
+use hal::peripherals::{PC2, PC3};
+use {ch32_hal as hal, panic_halt as _};
+
+use rv003usb::{dfu, usb, usb_composite};
+
+mod app;
+mod descriptors;
+mod dfu_mode;
+use dfu_mode::DfuMode;
+
+usb_composite! {
+    pub mod device for UsbIf<PC3, PC2> {
+        max_power: 100,
+        descriptors: descriptors::get_descriptor_info,
+        functions: {
+            dfu: dfu_mode() {
+                setup: |e, request, wvi, w_length| DFU.handle_setup(e, request, wvi, w_length),
+                control_out: |e, data| DFU.handle_download_data(e, data),
+                reset: || DFU.reset(),
+            },
+        }
+    }
+}
+
+static DFU: DfuMode = DfuMode::new(device::Interface::dfu.number());
+
+#[qingke_rt::entry]
+fn main() -> ! {
+    let request = dfu::BOOTLOADER_REQUEST_ADDR as *mut u32;
+    let requested = unsafe { request.read_volatile() } == dfu::BOOTLOADER_REQUEST_MAGIC;
+    unsafe { request.write_volatile(0) };
+    if !requested && app::valid() {
+        app::start();
+    }
+
+    let mut config = hal::Config::default();
+    config.rcc = rv003usb::clock::rcc_config();
+    let p = hal::init(config);
+
+    let mut delay = Delay;
+
+    // USB setup, same pins as the demo
+    let mut usb = device::new(p.PC3, p.PC2, p.PC5).start();
+    usb.connect();
+
+    loop {
+        DFU.poll();
+        if DFU.manifested() {
+            // Give dfu-util time to finish, then disconnect so the host
+            // enumerates the application
+            delay.delay_ms(500);
+            usb.disconnect();
+            delay.delay_ms(100);
+            usb::system_reset();
+        }
+    }
+}
@@ -1,6 +1,6 @@
 INCLUDE memory.x
-/* Provides weak aliases (cf. PROVIDED) for device specific interrupt handlers */
-/* This will usually be provided by a device crate generated using svd2rust (see `device.x`) */
+/* Provides weak aliases (cf. PROVIDED) for device specific interrupt handlers,
+   generated by build.rs together with the vector table in src/vectors.rs */
 INCLUDE device_usb.x
 
 PROVIDE(_stext = ORIGIN(REGION_TEXT));
@@ -0,0 +1,14 @@
+/* Application behind the DFU bootloader (feature layout-dfu-app) */
+MEMORY
+{
+    FLASH : ORIGIN = 0x00001800, LENGTH = 10K /* BANK_1 after the bootloader */
+    RAM   : ORIGIN = 0x20000000, LENGTH =  2K
+}
+REGION_ALIAS("REGION_TEXT", FLASH);
+REGION_ALIAS("REGION_RODATA", FLASH);
+REGION_ALIAS("REGION_DATA", RAM);
+REGION_ALIAS("REGION_BSS", RAM);
+REGION_ALIAS("REGION_HEAP", RAM);
+REGION_ALIAS("REGION_STACK", RAM);
+
+PROVIDE( _eusrstack = ORIGIN(RAM) + LENGTH(RAM));
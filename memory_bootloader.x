@@ -0,0 +1,16 @@
+/* DFU bootloader (feature layout-dfu-bootloader), the application follows at 6K */
+MEMORY
+{
+    /* The last page (0x17c0) holds the application length and CRC */
+    FLASH : ORIGIN = 0x00000000, LENGTH = 6K - 64
+    /* The last word is the bootloader request set by the application, see src/dfu.rs */
+    RAM   : ORIGIN = 0x20000000, LENGTH = 2K - 4
+}
+REGION_ALIAS("REGION_TEXT", FLASH);
+REGION_ALIAS("REGION_RODATA", FLASH);
+REGION_ALIAS("REGION_DATA", RAM);
+REGION_ALIAS("REGION_BSS", RAM);
+REGION_ALIAS("REGION_HEAP", RAM);
+REGION_ALIAS("REGION_STACK", RAM);
+
+PROVIDE( _eusrstack = ORIGIN(RAM) + LENGTH(RAM));
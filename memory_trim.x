@@ -0,0 +1,15 @@
+/* memory.x with feature persist-trim */
+MEMORY
+{
+    /* The last page (0x3fc0) holds the stored HSI trim, see src/trim_store.rs */
+    FLASH : ORIGIN = 0x00000000, LENGTH =   16K - 64 /* BANK_1 */
+    RAM   : ORIGIN = 0x20000000, LENGTH =    2K
+}
+REGION_ALIAS("REGION_TEXT", FLASH);
+REGION_ALIAS("REGION_RODATA", FLASH);
+REGION_ALIAS("REGION_DATA", RAM);
+REGION_ALIAS("REGION_BSS", RAM);
+REGION_ALIAS("REGION_HEAP", RAM);
+REGION_ALIAS("REGION_STACK", RAM);
+
+PROVIDE( _eusrstack = ORIGIN(RAM) + LENGTH(RAM));
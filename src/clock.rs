@@ -0,0 +1,68 @@
+// System clock the USB timing is built for
+//
+// The assembly is written for 32 cycles per low speed bit (1.5 MHz) and the
+// keepalive trimming counts the cycles of a 1 ms frame, so SYSCLK has to be
+// 48 MHz. The PLL doubles either the 24 MHz HSI (default, trimmed to the
+// host's frames) or a crystal (feature hse-24mhz). The crystal frequency is
+// taken from RV003USB_HSE_HZ at build time (24 MHz if unset), anything which
+// doesn't give 48 MHz fails to compile.
+use ch32_hal::rcc::Config;
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum ClockSource {
+    Hsi,
+    Hse { hz: u32 },
+}
+
+impl ClockSource {
+    pub const fn hz(self) -> u32 {
+        match self {
+            ClockSource::Hsi => HSI_HZ,
+            ClockSource::Hse { hz } => hz,
+        }
+    }
+}
+
+const HSI_HZ: u32 = 24_000_000;
+
+/// Multiplier of the PLL, the CH32V003 PLL only doubles
+pub const PLL_MUL: u32 = 2;
+
+#[cfg(not(feature = "hse-24mhz"))]
+pub const SOURCE: ClockSource = ClockSource::Hsi;
+#[cfg(feature = "hse-24mhz")]
+pub const SOURCE: ClockSource = ClockSource::Hse {
+    hz: parse_hz(env!("RV003USB_HSE_HZ")),
+};
+
+pub const SYSCLK_HZ: u32 = SOURCE.hz() * PLL_MUL;
+
+pub const CYCLES_PER_BIT: u32 = SYSCLK_HZ / 1_500_000;
+pub const CYCLES_PER_FRAME: u32 = SYSCLK_HZ / 1000;
+
+const _: () = assert!(
+    SYSCLK_HZ == 32 * 1_500_000,
+    "the USB bit timing needs a 48 MHz SYSCLK (24 MHz HSI or crystal through the PLL)"
+);
+
+// build.rs checked that it's a number
+#[cfg(feature = "hse-24mhz")]
+const fn parse_hz(s: &str) -> u32 {
+    let digits = s.as_bytes();
+    let mut hz = 0;
+    let mut i = 0;
+    while i < digits.len() {
+        hz = hz * 10 + (digits[i] - b'0') as u32;
+        i += 1;
+    }
+    hz
+}
+
+/// RCC configuration for ch32-hal's init matching [`SOURCE`], the PLL
+/// doubling the HSI or the crystal
+pub fn rcc_config() -> Config {
+    match SOURCE {
+        ClockSource::Hsi => Config::SYSCLK_FREQ_48MHZ_HSI,
+        ClockSource::Hse { .. } => Config::SYSCLK_FREQ_48MHZ_HSE,
+    }
+}
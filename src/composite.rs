@@ -0,0 +1,508 @@
+// Declarative composite device definition
+//
+// `usb_composite!` takes a list of functions and generates the configuration
+// descriptor, interface and endpoint numbering, report descriptor routing and
+// the dispatch of IN/OUT/setup packets to per-function handlers.
+//
+// Function n (counting from 0) gets interface n and endpoint n + 1, which is
+// used for both the IN and OUT direction. All endpoints are interrupt
+// endpoints with the low speed maximum packet size of 8 bytes.
+//
+// USB 2.0 (5.3.1.2) allows a low speed device only two endpoints besides
+// endpoint 0, counting IN and OUT separately, and hosts like Windows refuse
+// configurations with more. usb_composite! checks this at compile time, e.g.
+// two HID interfaces with an IN endpoint each or one with IN and OUT. Every HID
+// interface needs an IN endpoint, functions without endpoints (DFU, vendor)
+// don't count.
+//
+// The generated dispatch needs the user-out-data and user-setup features,
+// dfu_runtime functions the reboot feature.
+use crate::usb::EndpointConfig;
+
+const ENDPOINT_SIZE: u8 = 8;
+const POLL_INTERVAL: u8 = 10;
+
+pub const INTERFACE_DESC_LEN: usize = 9;
+// HID descriptor and DFU functional descriptor
+pub const CLASS_DESC_LEN: usize = 9;
+pub const ENDPOINT_DESC_LEN: usize = 7;
+/// Endpoints a low speed device may have besides endpoint 0
+pub const MAX_ENDPOINTS: usize = 2;
+
+/// Interface level description of a function in the configuration descriptor
+#[derive(Clone, Copy)]
+pub struct Function {
+    class: u8,
+    subclass: u8,
+    protocol: u8,
+    // Descriptor between the interface and endpoint descriptors
+    class_desc: Option<[u8; CLASS_DESC_LEN]>,
+    has_in: bool,
+    has_out: bool,
+}
+
+impl Function {
+    /// Boot protocol mouse
+    pub const fn hid_mouse(report_len: usize) -> Self {
+        Self::hid_with_protocol(0x01, 0x02, report_len)
+    }
+
+    /// Boot protocol keyboard
+    pub const fn hid_keyboard(report_len: usize) -> Self {
+        Self::hid_with_protocol(0x01, 0x01, report_len)
+    }
+
+    /// HID interface without boot protocol
+    pub const fn hid(report_len: usize) -> Self {
+        Self::hid_with_protocol(0x00, 0x00, report_len)
+    }
+
+    const fn hid_with_protocol(subclass: u8, protocol: u8, report_len: usize) -> Self {
+        let hid_desc = [
+            9,    // bLength
+            0x21, // bDescriptorType (HID)
+            0x10,
+            0x01, // bcd 1.1
+            0x00, // country code
+            0x01, // Num descriptors
+            0x22, // DescriptorType[0] (HID)
+            report_len as u8,
+            (report_len >> 8) as u8,
+        ];
+        Self {
+            class: 0x03,
+            subclass,
+            protocol,
+            class_desc: Some(hid_desc),
+            has_in: false,
+            has_out: false,
+        }
+    }
+
+    /// DFU 1.1 runtime interface, see [`crate::dfu`]
+    pub const fn dfu_runtime() -> Self {
+        Self {
+            class: 0xfe,    // Application specific
+            subclass: 0x01, // Device firmware upgrade
+            protocol: 0x01, // Runtime protocol
+            class_desc: Some(crate::dfu::RUNTIME_FUNCTIONAL_DESC),
+            has_in: false,
+            has_out: false,
+        }
+    }
+
+    /// DFU 1.1 interface of a device in DFU mode (bootloader)
+    pub const fn dfu_mode() -> Self {
+        Self {
+            class: 0xfe,    // Application specific
+            subclass: 0x01, // Device firmware upgrade
+            protocol: 0x02, // DFU mode protocol
+            class_desc: Some(crate::dfu::MODE_FUNCTIONAL_DESC),
+            has_in: false,
+            has_out: false,
+        }
+    }
+
+    /// Vendor specific interface
+    pub const fn vendor() -> Self {
+        Self {
+            class: 0xff,
+            subclass: 0x00,
+            protocol: 0x00,
+            class_desc: None,
+            has_in: false,
+            has_out: false,
+        }
+    }
+
+    pub const fn with_endpoints(mut self, has_in: bool, has_out: bool) -> Self {
+        self.has_in = has_in;
+        self.has_out = has_out;
+        self
+    }
+
+    /// Entry of the endpoint table for the endpoint of this function
+    pub const fn endpoint_config(&self) -> EndpointConfig {
+        if !self.has_in && !self.has_out {
+            return EndpointConfig::UNUSED;
+        }
+        EndpointConfig::interrupt(self.has_in, self.has_out, ENDPOINT_SIZE, POLL_INTERVAL)
+    }
+
+    const fn num_endpoints(&self) -> u8 {
+        self.has_in as u8 + self.has_out as u8
+    }
+
+    pub const fn descriptor_len(&self) -> usize {
+        let mut len = INTERFACE_DESC_LEN + self.num_endpoints() as usize * ENDPOINT_DESC_LEN;
+        if self.class_desc.is_some() {
+            len += CLASS_DESC_LEN;
+        }
+        len
+    }
+
+    /// Writes interface, class and endpoint descriptors at pos, returns the
+    /// position after them
+    const fn write_descriptor(&self, d: &mut [u8], mut pos: usize, interface: u8) -> usize {
+        let interface_desc = [
+            9,    // bLength
+            4,    // bDescriptorType
+            interface,
+            0,    // bAlternateSetting
+            self.num_endpoints(),
+            self.class,
+            self.subclass,
+            self.protocol,
+            0,    // iInterface
+        ];
+        pos = copy(d, pos, &interface_desc);
+        if let Some(class_desc) = &self.class_desc {
+            pos = copy(d, pos, class_desc);
+        }
+        let endpoint = interface + 1;
+        let config = self.endpoint_config();
+        if self.has_in {
+            pos = copy(d, pos, &config.descriptor(0x80 | endpoint));
+        }
+        if self.has_out {
+            pos = copy(d, pos, &config.descriptor(endpoint));
+        }
+        pos
+    }
+}
+
+/// Endpoint table for [`UsbIf::new`](crate::usb::UsbIf::new), N has to be
+/// the number of functions + 1
+pub const fn endpoint_table<const N: usize>(functions: &[Function]) -> [EndpointConfig; N] {
+    assert!(N == functions.len() + 1);
+    let mut table = [EndpointConfig::UNUSED; N];
+    table[0] = EndpointConfig::CONTROL;
+    let mut i = 0;
+    while i < functions.len() {
+        table[i + 1] = functions[i].endpoint_config();
+        i += 1;
+    }
+    table
+}
+
+const fn copy(d: &mut [u8], pos: usize, src: &[u8]) -> usize {
+    let mut i = 0;
+    while i < src.len() {
+        d[pos + i] = src[i];
+        i += 1;
+    }
+    pos + src.len()
+}
+
+/// Number of endpoint descriptors, at most [`MAX_ENDPOINTS`]
+pub const fn endpoint_count(functions: &[Function]) -> usize {
+    let mut count = 0;
+    let mut i = 0;
+    while i < functions.len() {
+        count += functions[i].num_endpoints() as usize;
+        i += 1;
+    }
+    count
+}
+
+pub const fn config_descriptor_len(functions: &[Function]) -> usize {
+    let mut len = 9;
+    let mut i = 0;
+    while i < functions.len() {
+        len += functions[i].descriptor_len();
+        i += 1;
+    }
+    len
+}
+
+/// Builds the configuration descriptor, N has to be config_descriptor_len()
+pub const fn config_descriptor<const N: usize>(functions: &[Function], max_power_ma: u16) -> [u8; N] {
+    assert!(N == config_descriptor_len(functions));
+    let mut d = [0; N];
+    let header = [
+        9, // bLength;
+        2, // bDescriptorType;
+        N as u8,
+        (N >> 8) as u8, // wTotalLength
+        functions.len() as u8, // bNumInterfaces
+        0x01, // bConfigurationValue
+        0x00, // iConfiguration
+        0x80, // bmAttributes (Bus powered)
+        (max_power_ma / 2) as u8, // bMaxPower (2mA units)
+    ];
+    let mut pos = copy(&mut d, 0, &header);
+    let mut i = 0;
+    while i < functions.len() {
+        pos = functions[i].write_descriptor(&mut d, pos, i as u8);
+        i += 1;
+    }
+    d
+}
+
+/// Defines a composite device in a new module.
+///
+/// ```ignore
+/// usb_composite! {
+///     pub mod device for UsbIf<hal::peripherals::PC3, hal::peripherals::PC2> {
+///         max_power: 200,
+///         // Handles everything but the configuration and report descriptors
+///         descriptors: descriptors::get_descriptor_info,
+///         functions: {
+///             mouse: hid_mouse(MouseReport, MOUSE_DESC_LEN) {
+///                 in: mouse_in,
+///             },
+///             raw: hid(RawHidReport, RAW_HID_DESC_LEN) {
+///                 in: |_e, usbif, sendtok| RAW_HID.handle_in(usbif, sendtok),
+///                 setup: |e, request, wvi, w_length| RAW_HID.handle_setup(e, request, wvi, w_length),
+///                 control_out: |e, data| RAW_HID.handle_feature_data(e, data),
+///             },
+///             dfu: dfu_runtime() {
+///                 setup: |e, request, wvi, w_length| DFU.handle_setup(e, request, wvi, w_length),
+///             },
+///             control: vendor() {
+///                 setup: vendor_setup,
+///             },
+///         }
+///     }
+/// }
+/// ```
+///
+/// Functions can have `#[cfg(...)]` attributes. At most [`MAX_ENDPOINTS`]
+/// endpoints (`in` and `out` handlers) are allowed in total.
+///
+/// Function kinds are `hid_mouse`, `hid_keyboard` and `hid` (report type and
+/// report descriptor length), `dfu_runtime`, `dfu_mode` and `vendor` (no
+/// arguments).
+/// A `dfu_runtime` function becomes the reboot trigger (DFU_DETACH is handled
+/// by the stack), its setup handler only has to answer the status requests. The handlers are
+/// optional but have to be given in this order, each followed by a comma:
+/// - `in: fn(*mut UsbEndpoint, &mut Usb, sendtok: u32)`, adds an IN endpoint
+/// - `out: fn(&mut UsbEndpoint, &[u8])`, adds an OUT endpoint
+/// - `setup: fn(&mut UsbEndpoint, request: u16, wvi: u32, w_length: u16) -> bool`,
+///   tried in order for control requests not handled by the stack until one
+///   returns true
+/// - `control_out: fn(&mut UsbEndpoint, &[u8])`, data stage of control writes
+///   accepted by the setup handler. If the setup handler set a max_len it has
+///   to advance the endpoint's count by one per packet, like
+///   [`RawHid::handle_feature_data`](crate::raw_hid::RawHid::handle_feature_data),
+///   so the status stage is a zero length packet
+/// - `control_in: fn(*mut UsbEndpoint, &mut Usb, sendtok: u32)`, IN tokens of
+///   control transfers the setup handler claimed with
+///   [`UsbEndpoint::claim`](crate::usb::UsbEndpoint::claim) (feature
+///   custom-ep0)
+/// - `reset: fn()`, called from the interrupt on a bus reset to return the
+///   function to its default state (e.g. report protocol)
+///
+/// The module handles the [`UsbEvent`](crate::usb::UsbEvent)s itself (a bus
+/// reset also ends the control transfer of the function which owned it), the
+/// optional `events: fn(UsbEvent)` after `descriptors` gets all of them
+/// afterwards. Don't replace it with `set_event_handler`.
+///
+/// The D+ and D- pin types go in place of the UsbIf parameters. The module
+/// defines the USB interrupt handler and provides `new(dp, dm, pull_up)`, the `Usb` type, `Interface::<name>` for the
+/// interface and endpoint numbers, the `ENDPOINT_TABLE` and `descriptors_valid()` to check the
+/// report descriptor lengths.
+#[macro_export]
+macro_rules! usb_composite {
+    (@function $kind:ident ()) => {
+        $crate::composite::Function::$kind()
+    };
+    (@function $kind:ident ($report:ty, $len:expr)) => {
+        $crate::composite::Function::$kind($len)
+    };
+    (@report ()) => {
+        None
+    };
+    (@report ($report:ty, $len:expr)) => {
+        Some(<$report as $crate::usbd_hid::descriptor::SerializedDescriptor>::desc())
+    };
+    (@check ()) => {
+        true
+    };
+    (@check ($report:ty, $len:expr)) => {
+        <$report as $crate::usbd_hid::descriptor::SerializedDescriptor>::desc().len() == $len
+    };
+    (@init $usb:ident, $name:ident, dfu_runtime) => {
+        $usb.set_reboot_trigger($crate::usb::RebootTrigger::DfuDetach(Interface::$name.number()))
+    };
+    (@init $usb:ident, $name:ident, $kind:ident) => {};
+    (@has) => {
+        false
+    };
+    (@has $handler:expr) => {
+        true
+    };
+    (
+        $vis:vis mod $module:ident for UsbIf<$dp:ty, $dm:ty> {
+            max_power: $max_power:expr,
+            descriptors: $fallback:expr,
+            $(events: $events:expr,)?
+            functions: {
+                $($(#[$meta:meta])* $name:ident: $kind:ident $args:tt {
+                    $(in: $in:expr,)?
+                    $(out: $out:expr,)?
+                    $(setup: $setup:expr,)?
+                    $(control_out: $control_out:expr,)?
+                    $(control_in: $control_in:expr,)?
+                    $(reset: $reset:expr,)?
+                }),* $(,)?
+            }
+        }
+    ) => {
+        $vis mod $module {
+            #![allow(dead_code, unused_variables)]
+            use super::*;
+            use core::sync::atomic::{AtomicU8, Ordering};
+            use $crate::usb::{UsbEndpoint, UsbEvent, UsbIf};
+
+            #[allow(non_camel_case_types)]
+            #[derive(Clone, Copy, PartialEq, Eq)]
+            pub enum Interface {
+                $($(#[$meta])* $name),*
+            }
+
+            impl Interface {
+                pub const fn number(self) -> u16 {
+                    self as u16
+                }
+
+                pub const fn endpoint(self) -> u8 {
+                    self as u8 + 1
+                }
+            }
+
+            const FUNCTIONS: &[$crate::composite::Function] = &[$(
+                $(#[$meta])*
+                $crate::usb_composite!(@function $kind $args).with_endpoints(
+                    $crate::usb_composite!(@has $($in)?),
+                    $crate::usb_composite!(@has $($out)?),
+                )
+            ),*];
+
+            pub const ENDPOINTS: usize = FUNCTIONS.len() + 1;
+            const _: () = assert!(
+                $crate::composite::endpoint_count(FUNCTIONS) <= $crate::composite::MAX_ENDPOINTS,
+                "a low speed device has at most 2 endpoints besides endpoint 0"
+            );
+            pub type Usb = UsbIf<
+                { <$dp as $crate::pins::UsbPin>::GPIO_BASE },
+                { <$dp as $crate::pins::UsbPin>::PIN },
+                { <$dm as $crate::pins::UsbPin>::PIN },
+                ENDPOINTS,
+            >;
+
+            pub const ENDPOINT_TABLE: [$crate::usb::EndpointConfig; ENDPOINTS] =
+                $crate::composite::endpoint_table(FUNCTIONS);
+
+            const CONFIG_DESCRIPTOR_LEN: usize = $crate::composite::config_descriptor_len(FUNCTIONS);
+            #[link_section = ".rodata"]
+            pub static CONFIG_DESCRIPTOR: [u8; CONFIG_DESCRIPTOR_LEN] =
+                $crate::composite::config_descriptor(FUNCTIONS, $max_power);
+
+            // Function which accepted the last control request
+            static CONTROL_OWNER: AtomicU8 = AtomicU8::new(u8::MAX);
+
+            static USB: $crate::usb::UsbCell<Usb> = $crate::usb::UsbCell::new();
+            $crate::usb_interrupt!(USB);
+
+            /// Takes the D+, D- and pull-up pins (see [`UsbIf::with_pins`]),
+            /// returns the state the interrupt handler uses for configuration
+            /// until [`UsbIf::start`]. Call once.
+            pub fn new(dp: $dp, dm: $dm, pull_up: impl $crate::pins::Pin) -> &'static mut Usb {
+                let mut usb = UsbIf::with_pins(dp, dm, pull_up, ENDPOINT_TABLE, in_request, get_descriptor_info);
+                usb.set_user_data_handler(user_data);
+                usb.set_user_setup_handler(user_setup);
+                usb.set_event_handler(event);
+                $($(#[$meta])* $crate::usb_composite!(@init usb, $name, $kind);)*
+                USB.init(usb)
+            }
+
+            /// Checks the hardcoded report descriptor lengths
+            pub fn descriptors_valid() -> bool {
+                let mut valid = true;
+                $($(#[$meta])* {
+                    valid &= $crate::usb_composite!(@check $args);
+                })*
+                valid
+            }
+
+            pub fn get_descriptor_info(w_value: u32) -> (*const u8, u16) {
+                if w_value == 0x00000200 {
+                    return (CONFIG_DESCRIPTOR.as_ptr(), CONFIG_DESCRIPTOR.len() as u16);
+                }
+                // HID Report (0x22), the interface is in wIndex
+                if w_value & 0xffff == 0x2200 {
+                    let interface = w_value >> 16;
+                    $($(#[$meta])* {
+                        if interface == Interface::$name as u32 {
+                            let report: Option<&[u8]> = $crate::usb_composite!(@report $args);
+                            if let Some(report) = report {
+                                return (report.as_ptr(), report.len() as u16);
+                            }
+                        }
+                    })*
+                }
+                ($fallback)(w_value)
+            }
+
+            fn in_request(e: *mut UsbEndpoint, _scratchpad: *mut u8, endp: i32, sendtok: u32, usbif: &mut Usb) {
+                if endp == 0 {
+                    let owner = CONTROL_OWNER.load(Ordering::Relaxed);
+                    $($(#[$meta])* {$(
+                        if owner == Interface::$name as u8 {
+                            ($control_in)(e, usbif, sendtok);
+                            return;
+                        }
+                    )?})*
+                }
+                $($(#[$meta])* {$(
+                    if endp == Interface::$name.endpoint() as i32 {
+                        ($in)(e, usbif, sendtok);
+                        return;
+                    }
+                )?})*
+                // If it's a control transfer, empty it.
+                usbif.usb_send_empty(sendtok);
+            }
+
+            fn user_data(e: &mut UsbEndpoint, endp: u32, data: &[u8]) {
+                if endp == 0 {
+                    let owner = CONTROL_OWNER.load(Ordering::Relaxed);
+                    $($(#[$meta])* {$(
+                        if owner == Interface::$name as u8 {
+                            ($control_out)(e, data);
+                            return;
+                        }
+                    )?})*
+                    return;
+                }
+                $($(#[$meta])* {$(
+                    if endp == Interface::$name.endpoint() as u32 {
+                        ($out)(e, data);
+                        return;
+                    }
+                )?})*
+            }
+
+            fn event(event: UsbEvent) {
+                if event == UsbEvent::Reset {
+                    CONTROL_OWNER.store(u8::MAX, Ordering::Relaxed);
+                    $($(#[$meta])* {$(
+                        ($reset)();
+                    )?})*
+                }
+                $(($events)(event);)?
+            }
+
+            fn user_setup(e: &mut UsbEndpoint, request: u16, wvi: u32, w_length: u16) {
+                CONTROL_OWNER.store(u8::MAX, Ordering::Relaxed);
+                $($(#[$meta])* {$(
+                    if ($setup)(&mut *e, request, wvi, w_length) {
+                        CONTROL_OWNER.store(Interface::$name as u8, Ordering::Relaxed);
+                        return;
+                    }
+                )?})*
+            }
+        }
+    };
+}
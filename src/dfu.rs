@@ -0,0 +1,93 @@
+// USB DFU 1.1 runtime interface
+//
+// Lets dfu-util (`dfu-util -e`) reboot the device into the bootloader. The
+// stack handles DFU_DETACH itself with `RebootTrigger::DfuDetach` (set by
+// usb_composite! for dfu_runtime functions) and reboots to the reboot target
+// after the status stage, as bitWillDetach is set the host doesn't have to
+// reset the bus.
+//
+// The DFU mode side is the bootloader in examples/dfu_bootloader.
+use crate::usb::{system_reset, UsbEndpoint};
+
+// bmAttributes
+const WILL_DETACH: u8 = 1 << 3;
+const MANIFESTATION_TOLERANT: u8 = 1 << 2;
+const CAN_UPLOAD: u8 = 1 << 1;
+const CAN_DNLOAD: u8 = 1 << 0;
+
+/// Time the host waits for the device to re-enumerate after DFU_DETACH
+pub const DETACH_TIMEOUT_MS: u16 = 1000;
+/// Block size for DNLOAD / UPLOAD, one flash page
+pub const TRANSFER_SIZE: u16 = 64;
+
+pub const RUNTIME_FUNCTIONAL_DESC: [u8; 9] = [
+    9,    // bLength
+    0x21, // bDescriptorType (DFU functional)
+    WILL_DETACH | CAN_UPLOAD | CAN_DNLOAD, // bmAttributes
+    DETACH_TIMEOUT_MS as u8,
+    (DETACH_TIMEOUT_MS >> 8) as u8, // wDetachTimeOut
+    TRANSFER_SIZE as u8,
+    (TRANSFER_SIZE >> 8) as u8, // wTransferSize
+    0x10,
+    0x01, // bcdDFUVersion 1.1
+];
+
+pub const MODE_FUNCTIONAL_DESC: [u8; 9] = [
+    9,    // bLength
+    0x21, // bDescriptorType (DFU functional)
+    MANIFESTATION_TOLERANT | CAN_UPLOAD | CAN_DNLOAD, // bmAttributes
+    DETACH_TIMEOUT_MS as u8,
+    (DETACH_TIMEOUT_MS >> 8) as u8, // wDetachTimeOut
+    TRANSFER_SIZE as u8,
+    (TRANSFER_SIZE >> 8) as u8, // wTransferSize
+    0x10,
+    0x01, // bcdDFUVersion 1.1
+];
+
+/// Last word of RAM, checked by the DFU bootloader after a reset. The
+/// bootloader doesn't use it (see memory_bootloader.x).
+pub const BOOTLOADER_REQUEST_ADDR: usize = 0x2000_07fc;
+pub const BOOTLOADER_REQUEST_MAGIC: u32 = 0xdf11_b007;
+
+/// Resets into the DFU bootloader of examples/dfu_bootloader (app built with
+/// feature layout-dfu-app), unlike [`crate::usb::reboot_to_bootloader`] which
+/// starts the factory bootloader.
+pub fn reboot_to_dfu_bootloader() -> ! {
+    unsafe { (BOOTLOADER_REQUEST_ADDR as *mut u32).write_volatile(BOOTLOADER_REQUEST_MAGIC) };
+    system_reset();
+}
+
+// bStatus OK, bwPollTimeout 0, bState appIDLE, iString 0. The device reboots
+// right after DFU_DETACH, so appDETACH is never reported.
+static APP_IDLE_STATUS: [u8; 6] = [0x00, 0x00, 0x00, 0x00, 0x00, 0x00];
+static APP_IDLE_STATE: [u8; 1] = [0x00];
+
+pub struct DfuRuntime {
+    interface: u16,
+}
+
+impl DfuRuntime {
+    pub const fn new(interface: u16) -> Self {
+        Self { interface }
+    }
+
+    /// Call from the user setup handler, handles DFU_GETSTATUS and
+    /// DFU_GETSTATE. Returns true if the request was for this interface.
+    pub fn handle_setup(&self, e: &mut UsbEndpoint, request: u16, wvi: u32, w_length: u16) -> bool {
+        let interface = (wvi >> 16) as u16;
+        if interface != self.interface {
+            return false;
+        }
+        let data: &[u8] = if request == 0x03a1 {
+            // DFU_GETSTATUS
+            &APP_IDLE_STATUS
+        } else if request == 0x05a1 {
+            // DFU_GETSTATE
+            &APP_IDLE_STATE
+        } else {
+            return false;
+        };
+        e.set_in_data(data.as_ptr(), (w_length as u32).min(data.len() as u32));
+        true
+    }
+}
@@ -0,0 +1,126 @@
+// CH32V003 flash controller, fast (64 byte page) erase and programming
+//
+// Code keeps running from flash while a page is written, the CPU just stalls
+// until the controller is done. The USB interrupt is blocked meanwhile, the
+// host retries and the trimming drops the long frame as outlier.
+use ch32_hal::pac::FLASH;
+
+pub const PAGE_SIZE: usize = 64;
+/// Size of the code flash
+pub const FLASH_SIZE: u32 = 16 * 1024;
+/// The last page holds the HSI trim with feature persist-trim, which selects a
+/// memory.x without it (memory_trim.x, memory_app_trim.x)
+pub const TRIM_PAGE_OFFSET: u32 = FLASH_SIZE - PAGE_SIZE as u32;
+/// Code flash as seen by the flash controller, the same memory is mapped at 0
+const FLASH_BASE: u32 = 0x0800_0000;
+
+const KEY1: u32 = 0x45670123;
+const KEY2: u32 = 0xCDEF89AB;
+
+// Register offsets
+const KEYR: usize = 0x04;
+const STATR: usize = 0x0c;
+const CTLR: usize = 0x10;
+const ADDR: usize = 0x14;
+const MODEKEYR: usize = 0x24;
+
+// STATR
+const BSY: u32 = 1 << 0;
+const WRPRTERR: u32 = 1 << 4;
+const EOP: u32 = 1 << 5;
+// CTLR
+const STRT: u32 = 1 << 6;
+const LOCK: u32 = 1 << 7;
+const FLOCK: u32 = 1 << 15;
+const FTPG: u32 = 1 << 16;
+const FTER: u32 = 1 << 17;
+const BUFLOAD: u32 = 1 << 18;
+const BUFRST: u32 = 1 << 19;
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Error {
+    /// Write protected page
+    Protected,
+    /// Content doesn't match after erasing or programming
+    Verify,
+}
+
+fn reg(offset: usize) -> *mut u32 {
+    (FLASH.as_ptr() as usize + offset) as *mut u32
+}
+
+fn read_reg(offset: usize) -> u32 {
+    unsafe { reg(offset).read_volatile() }
+}
+
+fn write_reg(offset: usize, value: u32) {
+    unsafe { reg(offset).write_volatile(value) };
+}
+
+fn wait_busy() -> Result<(), Error> {
+    while read_reg(STATR) & BSY != 0 {}
+    let statr = read_reg(STATR);
+    // Both flags are cleared by writing 1
+    write_reg(STATR, statr & (WRPRTERR | EOP));
+    if statr & WRPRTERR != 0 {
+        Err(Error::Protected)
+    } else {
+        Ok(())
+    }
+}
+
+/// Unlocks the controller including fast programming for the closure
+fn unlocked<R>(f: impl FnOnce() -> R) -> R {
+    write_reg(KEYR, KEY1);
+    write_reg(KEYR, KEY2);
+    write_reg(MODEKEYR, KEY1);
+    write_reg(MODEKEYR, KEY2);
+    let result = f();
+    write_reg(CTLR, LOCK | FLOCK);
+    result
+}
+
+/// Erases the page at offset (from the start of flash) and programs data into
+/// it, missing bytes at the end are left erased (0xff).
+pub fn write_page(offset: u32, data: &[u8]) -> Result<(), Error> {
+    debug_assert!(offset as usize % PAGE_SIZE == 0 && data.len() <= PAGE_SIZE);
+    let mut page = [0xff; PAGE_SIZE];
+    page[..data.len()].copy_from_slice(data);
+    let address = FLASH_BASE + offset;
+
+    unlocked(|| {
+        write_reg(CTLR, FTER);
+        write_reg(ADDR, address);
+        write_reg(CTLR, FTER | STRT);
+        wait_busy()?;
+
+        write_reg(CTLR, FTPG);
+        write_reg(CTLR, FTPG | BUFRST);
+        wait_busy()?;
+        for (i, word) in page.chunks_exact(4).enumerate() {
+            let word = u32::from_le_bytes([word[0], word[1], word[2], word[3]]);
+            unsafe { ((address as usize + i * 4) as *mut u32).write_volatile(word) };
+            write_reg(CTLR, FTPG | BUFLOAD);
+            wait_busy()?;
+        }
+        write_reg(ADDR, address);
+        write_reg(CTLR, FTPG | STRT);
+        wait_busy()?;
+        write_reg(CTLR, 0);
+        Ok(())
+    })?;
+
+    if read_page(offset) != &page {
+        return Err(Error::Verify);
+    }
+    Ok(())
+}
+
+pub fn read_page(offset: u32) -> &'static [u8; PAGE_SIZE] {
+    unsafe { &*((FLASH_BASE + offset) as *const [u8; PAGE_SIZE]) }
+}
+
+/// Flash contents starting at offset
+pub fn read(offset: u32, len: usize) -> &'static [u8] {
+    unsafe { core::slice::from_raw_parts((FLASH_BASE + offset) as *const u8, len) }
+}
@@ -0,0 +1,294 @@
+// Gamepad / joystick HID report with a configurable number of buttons and axes
+//
+// Unlike the other HID reports this one doesn't use gen_hid_descriptor: it
+// takes the logical range from the field type and can't express the 0-7 range
+// (with a null state) of a hat switch. The report descriptor is built by
+// gamepad_descriptor instead, the same function gives its length, so the
+// configuration descriptor can't disagree with it.
+
+/// Defines a gamepad report type and the length of its report descriptor.
+///
+/// `buttons` is the type holding the button bits (u8, u16 or u32) and the
+/// number of buttons, `axes` the number of 8 bit axes (1-6, X, Y, Z, Rx, Ry,
+/// Rz in that order).
+///
+/// ```ignore
+/// gamepad_report!(pub struct ArcadeReport, ARCADE_DESC_LEN { buttons: u16 = 12, axes: 2 });
+/// ```
+///
+/// The report is laid out as buttons (little endian), the hat switch in the
+/// low 4 bits of a byte (see [`Hat::value`]) and one signed byte per axis,
+/// see `to_bytes`. It's sent as a single packet, so it can't be longer than 8
+/// bytes (e.g. u32 buttons leave room for 3 axes), which is checked at compile
+/// time.
+#[macro_export]
+macro_rules! gamepad_report {
+    ($vis:vis struct $name:ident, $len:ident { buttons: $bty:ident = $buttons:expr, axes: $axes:expr }) => {
+        #[derive(Clone, Copy)]
+        $vis struct $name {
+            pub buttons: $bty,
+            pub hat: $crate::gamepad::Hat,
+            pub axes: [i8; $axes],
+        }
+
+        const _: () = assert!(
+            core::mem::size_of::<$bty>() + 1 + $axes <= 8,
+            "gamepad report longer than a low speed packet (8 bytes)"
+        );
+
+        $vis const $len: usize =
+            $crate::gamepad::gamepad_desc_len($buttons, core::mem::size_of::<$bty>() * 8, $axes);
+
+        impl $name {
+            pub fn to_bytes(&self) -> [u8; core::mem::size_of::<$bty>() + 1 + $axes] {
+                let mut report = [0; core::mem::size_of::<$bty>() + 1 + $axes];
+                let buttons = self.buttons.to_le_bytes();
+                report[..buttons.len()].copy_from_slice(&buttons);
+                report[buttons.len()] = self.hat.value();
+                for (byte, axis) in report[buttons.len() + 1..].iter_mut().zip(self.axes) {
+                    *byte = axis as u8;
+                }
+                report
+            }
+        }
+
+        impl $crate::usbd_hid::descriptor::SerializedDescriptor for $name {
+            fn desc() -> &'static [u8] {
+                static DESC: [u8; $len] = $crate::gamepad::gamepad_descriptor(
+                    $buttons,
+                    core::mem::size_of::<$bty>() * 8,
+                    $axes,
+                );
+                &DESC
+            }
+        }
+    };
+}
+
+// Writes the report descriptor to out (as far as it fits) and returns its
+// length
+const fn write_descriptor(out: &mut [u8], buttons: usize, button_bits: usize, axes: usize) -> usize {
+    assert!(buttons > 0 && buttons <= button_bits && button_bits <= 32);
+    assert!(axes > 0 && axes <= 6);
+    let padding = (button_bits - buttons) as u8;
+    let buttons = buttons as u8;
+    let axes = axes as u8;
+
+    let mut items = [0u8; 80];
+    let mut len = 0;
+    macro_rules! push {
+        ($($byte:expr),* $(,)?) => {
+            $(
+                items[len] = $byte;
+                len += 1;
+            )*
+        };
+    }
+    push!(
+        0x05, 0x01, // Usage Page (Generic Desktop)
+        0x09, 0x05, // Usage (Game Pad)
+        0xA1, 0x01, // Collection (Application)
+        0x05, 0x09, //   Usage Page (Button)
+        0x19, 0x01, //   Usage Minimum (1)
+        0x29, buttons, // Usage Maximum
+        0x15, 0x00, //   Logical Minimum (0)
+        0x25, 0x01, //   Logical Maximum (1)
+        0x75, 0x01, //   Report Size (1)
+        0x95, buttons, // Report Count
+        0x81, 0x02, //   Input (Data, Variable, Absolute)
+    );
+    if padding > 0 {
+        push!(
+            0x95, padding, // Report Count
+            0x81, 0x03, //   Input (Constant, Variable, Absolute)
+        );
+    }
+    push!(
+        0x05, 0x01, //   Usage Page (Generic Desktop)
+        0x09, 0x39, //   Usage (Hat Switch)
+        0x15, 0x00, //   Logical Minimum (0)
+        0x25, 0x07, //   Logical Maximum (7)
+        0x35, 0x00, //   Physical Minimum (0)
+        0x46, 0x3B, 0x01, // Physical Maximum (315)
+        0x65, 0x14, //   Unit (Degrees)
+        0x75, 0x04, //   Report Size (4)
+        0x95, 0x01, //   Report Count (1)
+        0x81, 0x42, //   Input (Data, Variable, Absolute, Null State)
+        0x65, 0x00, //   Unit (None)
+        0x81, 0x03, //   Input (Constant, Variable, Absolute), 4 bit padding
+        0x19, 0x30, //   Usage Minimum (X)
+        0x29, 0x30 + axes - 1, // Usage Maximum
+        0x15, 0x81, //   Logical Minimum (-127)
+        0x25, 0x7F, //   Logical Maximum (127)
+        0x35, 0x00, //   Physical Minimum (0)
+        0x45, 0x00, //   Physical Maximum (0, same as logical)
+        0x75, 0x08, //   Report Size (8)
+        0x95, axes, //   Report Count
+        0x81, 0x02, //   Input (Data, Variable, Absolute)
+        0xC0, //       End Collection
+    );
+
+    let mut i = 0;
+    while i < len && i < out.len() {
+        out[i] = items[i];
+        i += 1;
+    }
+    len
+}
+
+/// Length of the report descriptor of [`gamepad_report`]
+pub const fn gamepad_desc_len(buttons: usize, button_bits: usize, axes: usize) -> usize {
+    write_descriptor(&mut [], buttons, button_bits, axes)
+}
+
+/// Report descriptor of [`gamepad_report`], LEN has to be
+/// [`gamepad_desc_len`] of the same arguments
+pub const fn gamepad_descriptor<const LEN: usize>(buttons: usize, button_bits: usize, axes: usize) -> [u8; LEN] {
+    let mut desc = [0; LEN];
+    assert!(write_descriptor(&mut desc, buttons, button_bits, axes) == LEN);
+    desc
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Hat {
+    Centered,
+    Up,
+    UpRight,
+    Right,
+    DownRight,
+    Down,
+    DownLeft,
+    Left,
+    UpLeft,
+}
+
+impl Hat {
+    /// Hat switch value: 0-7 clockwise from up, 8 (out of the logical range,
+    /// the null state) when centered
+    pub const fn value(self) -> u8 {
+        match self {
+            Hat::Up => 0,
+            Hat::UpRight => 1,
+            Hat::Right => 2,
+            Hat::DownRight => 3,
+            Hat::Down => 4,
+            Hat::DownLeft => 5,
+            Hat::Left => 6,
+            Hat::UpLeft => 7,
+            Hat::Centered => 8,
+        }
+    }
+
+    /// Inverse of [`value`](Self::value), anything above 7 is centered
+    pub const fn from_value(value: u8) -> Self {
+        match value {
+            0 => Hat::Up,
+            1 => Hat::UpRight,
+            2 => Hat::Right,
+            3 => Hat::DownRight,
+            4 => Hat::Down,
+            5 => Hat::DownLeft,
+            6 => Hat::Left,
+            7 => Hat::UpLeft,
+            _ => Hat::Centered,
+        }
+    }
+
+    /// Combines four direction switches, opposing directions cancel out
+    pub const fn from_switches(up: bool, down: bool, left: bool, right: bool) -> Self {
+        let y = up as i8 - down as i8;
+        let x = right as i8 - left as i8;
+        match (x, y) {
+            (0, 1) => Hat::Up,
+            (1, 1) => Hat::UpRight,
+            (1, 0) => Hat::Right,
+            (1, -1) => Hat::DownRight,
+            (0, -1) => Hat::Down,
+            (-1, -1) => Hat::DownLeft,
+            (-1, 0) => Hat::Left,
+            (-1, 1) => Hat::UpLeft,
+            _ => Hat::Centered,
+        }
+    }
+}
+
+/// Maps raw ADC readings onto a signed axis value, with separate scaling for
+/// both sides of the center so off-center potentiometers still reach the full
+/// range.
+#[derive(Clone, Copy)]
+pub struct AxisCalibration {
+    pub min: u16,
+    pub center: u16,
+    pub max: u16,
+}
+
+impl AxisCalibration {
+    /// Full range of the 10 bit ADC
+    pub const ADC_10BIT: Self = Self::new(0, 512, 1023);
+
+    /// Panics unless min <= center <= max
+    pub const fn new(min: u16, center: u16, max: u16) -> Self {
+        assert!(min <= center && center <= max);
+        Self { min, center, max }
+    }
+
+    /// Saturates (instead of overflowing) if the fields were set without the
+    /// check of [`new`](Self::new)
+    pub fn scale(&self, raw: u16) -> i8 {
+        if raw < self.center {
+            let span = self.center.saturating_sub(self.min).max(1) as i32;
+            let offset = self.center.saturating_sub(raw.max(self.min)) as i32;
+            (-(offset * 127) / span) as i8
+        } else {
+            let span = self.max.saturating_sub(self.center).max(1) as i32;
+            let offset = raw.min(self.max).saturating_sub(self.center) as i32;
+            ((offset * 127) / span) as i8
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn descriptor_matches_length() {
+        const LEN: usize = gamepad_desc_len(12, 16, 2);
+        let desc: [u8; LEN] = gamepad_descriptor(12, 16, 2);
+        assert_eq!(LEN, 70);
+        // Hat switch with a logical range of 0-7 and a null state
+        let hat = desc.windows(2).position(|w| w == [0x09, 0x39]).unwrap();
+        assert_eq!(desc[hat + 2..hat + 6], [0x15, 0x00, 0x25, 0x07]);
+        assert!(desc.windows(2).any(|w| w == [0x81, 0x42]));
+        assert_eq!(desc[LEN - 1], 0xC0);
+        // No padding when the buttons fill their type
+        assert_eq!(gamepad_desc_len(8, 8, 6), LEN - 4);
+    }
+
+    #[test]
+    fn hat_values() {
+        for value in 0..=8 {
+            assert_eq!(Hat::from_value(value).value(), value);
+        }
+        assert!(Hat::from_switches(true, true, false, false) == Hat::Centered);
+        assert_eq!(Hat::from_switches(false, true, true, false).value(), 5);
+    }
+
+    #[test]
+    fn axis_scale() {
+        let axis = AxisCalibration::new(100, 400, 1000);
+        assert_eq!(axis.scale(0), -127);
+        assert_eq!(axis.scale(400), 0);
+        assert_eq!(axis.scale(1023), 127);
+        // Fields set past the check of new() saturate instead of overflowing
+        let axis = AxisCalibration { min: 500, center: 400, max: 300 };
+        assert_eq!(axis.scale(0), 0);
+        assert_eq!(axis.scale(1023), 0);
+    }
+
+    #[test]
+    #[should_panic]
+    fn axis_calibration_checked() {
+        AxisCalibration::new(0, 1024, 1023);
+    }
+}
@@ -0,0 +1,124 @@
+// Standard HID reports. Their report descriptor lengths are hardcoded for the
+// configuration descriptor (see `MOUSE_DESC_LEN`), usb_composite! checks them
+// against the generated descriptors at runtime.
+use usbd_hid::descriptor::generator_prelude::*;
+
+#[gen_hid_descriptor(
+    (collection = APPLICATION, usage_page = GENERIC_DESKTOP, usage = MOUSE) = {
+        (collection = PHYSICAL, usage = POINTER) = {
+            (usage_page = BUTTON, usage_min = BUTTON_1, usage_max = BUTTON_3) = {
+                #[packed_bits 3] #[item_settings data,variable,absolute] buttons=input;
+            };
+            (usage_page = GENERIC_DESKTOP,) = {
+                (usage = X,) = {#[item_settings data,variable,relative] x=input};
+                (usage = Y,) = {#[item_settings data,variable,relative] y=input};
+                (usage = WHEEL,) = {#[item_settings data,variable,relative] wheel=input};
+            }
+        }
+    }
+)]
+pub struct MouseReport {
+    pub buttons: u8,
+    pub x: i8,
+    pub y: i8,
+    pub wheel: i8,
+}
+// We need this value for the configuration descriptor, unfortunately there is no way
+// to get the hid descriptor statically. Thus hardcode it here and verify that it matches later on.
+// How do you get the real length without guessing? I just made a separate project that printed it
+pub const MOUSE_DESC_LEN: usize = 57;
+
+#[gen_hid_descriptor(
+    (collection = APPLICATION, usage_page = GENERIC_DESKTOP, usage = KEYBOARD) = {
+        (usage_page = KEYBOARD, usage_min = 0xE0, usage_max = 0xE7) = {
+            #[packed_bits 8] #[item_settings data,variable,absolute] modifier=input;
+        };
+        (usage_min = 0x00, usage_max = 0xFF) = {
+            #[item_settings constant,variable,absolute] reserved=input;
+        };
+        (usage_page = LEDS, usage_min = 0x01, usage_max = 0x05) = {
+            #[packed_bits 5] #[item_settings data,variable,absolute] leds=output;
+        };
+        (usage_page = KEYBOARD, usage_min = 0x00, usage_max = 0xDD) = {
+            #[item_settings data,array,absolute] keycodes=input;
+        };
+    }
+)]
+pub struct KeyboardReport {
+    pub modifier: u8,
+    pub reserved: u8,
+    pub leds: u8,
+    pub keycodes: [u8; 6],
+}
+pub const KBD_DESC_LEN: usize = 69;
+
+#[gen_hid_descriptor(
+    (collection = APPLICATION, usage_page = CONSUMER, usage = 0x01) = {
+        (usage_page = CONSUMER, usage_min = 0x00, usage_max = 0x514) = {
+            #[item_settings data,array,absolute,not_null] usage_id=input;
+        };
+    }
+)]
+pub struct ConsumerReport {
+    pub usage_id: u16,
+}
+pub const CONSUMER_DESC_LEN: usize = 27;
+
+// A few common consumer usages (HID Usage Tables, chapter 15), 0 = released
+pub mod consumer {
+    pub const BRIGHTNESS_INCREMENT: u16 = 0x006F;
+    pub const BRIGHTNESS_DECREMENT: u16 = 0x0070;
+    pub const SCAN_NEXT_TRACK: u16 = 0x00B5;
+    pub const SCAN_PREVIOUS_TRACK: u16 = 0x00B6;
+    pub const STOP: u16 = 0x00B7;
+    pub const PLAY_PAUSE: u16 = 0x00CD;
+    pub const MUTE: u16 = 0x00E2;
+    pub const VOLUME_INCREMENT: u16 = 0x00E9;
+    pub const VOLUME_DECREMENT: u16 = 0x00EA;
+}
+
+#[gen_hid_descriptor(
+    (collection = APPLICATION, usage_page = GENERIC_DESKTOP, usage = MOUSE) = {
+        (collection = PHYSICAL, usage = POINTER) = {
+            (usage_page = BUTTON, usage_min = BUTTON_1, usage_max = BUTTON_3) = {
+                #[packed_bits 3] #[item_settings data,variable,absolute] buttons=input;
+            };
+            (usage_page = GENERIC_DESKTOP,) = {
+                (usage = X,) = {#[item_settings data,variable,absolute] x=input};
+                (usage = Y,) = {#[item_settings data,variable,absolute] y=input};
+            }
+        }
+    }
+)]
+pub struct AbsolutePointerReport {
+    pub buttons: u8,
+    pub x: u16,
+    pub y: u16,
+}
+pub const ABS_POINTER_DESC_LEN: usize = 51;
+
+impl AbsolutePointerReport {
+    /// Maps a position on a screen of the given size onto the full 16 bit
+    /// range, the host scales it back to its own resolution.
+    pub fn from_screen(buttons: u8, x: u16, y: u16, width: u16, height: u16) -> Self {
+        Self {
+            buttons,
+            x: scale_to_u16(x, width),
+            y: scale_to_u16(y, height),
+        }
+    }
+
+    pub fn to_bytes(&self) -> [u8; 5] {
+        let x = self.x.to_le_bytes();
+        let y = self.y.to_le_bytes();
+        [self.buttons, x[0], x[1], y[0], y[1]]
+    }
+}
+
+fn scale_to_u16(value: u16, size: u16) -> u16 {
+    if size <= 1 {
+        return 0;
+    }
+    let value = value.min(size - 1) as u32;
+    (value * u16::MAX as u32 / (size - 1) as u32) as u16
+}
@@ -0,0 +1,49 @@
+//! Software low speed USB device for the CH32V003, a port of
+//! [rv003usb](https://github.com/cnlohr/rv003usb).
+//!
+//! [`usb::UsbIf`] is the engine, called from the EXTI interrupt of the D-
+//! pin, [`usb_composite!`] builds a composite device on top of it and the
+//! other modules are ready made functions. See the examples for complete
+//! firmware.
+//!
+//! Link with `-Tlink_usb.x` (the build script puts it and a `memory.x` on the
+//! linker search path), it places the vector table and `EXTI7_0_IRQHandler`
+//! in RAM (`.highcode`). The receive handler, `usb_send_data` and the token
+//! handlers stay in flash, their delay loops are cycle counted for flash
+//! execution with 1 wait state. `EXTI7_0_IRQHandler` comes from
+//! [`usb_interrupt!`] (invoked by `usb_composite!`).
+#![no_std]
+
+pub use usbd_hid;
+
+#[cfg(feature = "hal")]
+#[doc(hidden)]
+pub mod __private {
+    pub use ch32_hal::interrupt;
+}
+
+#[cfg(feature = "hal")]
+pub mod clock;
+#[cfg(feature = "hal")]
+pub mod composite;
+pub mod descriptors;
+#[cfg(feature = "hal")]
+pub mod dfu;
+#[cfg(feature = "hal")]
+pub mod flash;
+pub mod gamepad;
+pub mod hid;
+#[cfg(feature = "hal")]
+pub mod nkro;
+#[cfg(feature = "hal")]
+pub mod pins;
+#[cfg(feature = "hal")]
+pub mod raw_hid;
+pub mod trim;
+#[cfg(feature = "persist-trim")]
+pub mod trim_store;
+pub mod typing;
+#[cfg(feature = "hal")]
+pub mod usb;
+#[cfg(feature = "hal")]
+mod vectors;
@@ -0,0 +1,181 @@
+// N-key rollover keyboard
+//
+// The report is a bitmap of all keys, which is larger than the 8 bytes a low
+// speed endpoint can send at once, so it's sent in multiple packets. The
+// interface is still a boot keyboard, if the host selects the boot protocol
+// (BIOS, bootloaders, ...) the 6KRO boot report is sent instead.
+use core::sync::atomic::{AtomicU8, Ordering};
+
+use usbd_hid::descriptor::generator_prelude::*;
+
+use crate::usb::{UsbEndpoint, UsbIf};
+
+#[gen_hid_descriptor(
+    (collection = APPLICATION, usage_page = GENERIC_DESKTOP, usage = KEYBOARD) = {
+        (usage_page = KEYBOARD, usage_min = 0xE0, usage_max = 0xE7) = {
+            #[packed_bits 8] #[item_settings data,variable,absolute] modifier=input;
+        };
+        (usage_page = LEDS, usage_min = 0x01, usage_max = 0x05) = {
+            #[packed_bits 5] #[item_settings data,variable,absolute] leds=output;
+        };
+        (usage_page = KEYBOARD, usage_min = 0x00, usage_max = 0x1F) = {
+            #[packed_bits 32] #[item_settings data,variable,absolute] keys0=input;
+        };
+        (usage_min = 0x20, usage_max = 0x3F) = {
+            #[packed_bits 32] #[item_settings data,variable,absolute] keys1=input;
+        };
+        (usage_min = 0x40, usage_max = 0x5F) = {
+            #[packed_bits 32] #[item_settings data,variable,absolute] keys2=input;
+        };
+        (usage_min = 0x60, usage_max = 0x6F) = {
+            #[packed_bits 16] #[item_settings data,variable,absolute] keys3=input;
+        };
+        (usage_min = 0x70, usage_max = 0x77) = {
+            #[packed_bits 8] #[item_settings data,variable,absolute] keys4=input;
+        };
+    }
+)]
+#[allow(dead_code)]
+pub struct NkroKeyboardReport {
+    pub modifier: u8,
+    pub leds: u8,
+    pub keys0: u32,
+    pub keys1: u32,
+    pub keys2: u32,
+    pub keys3: u16,
+    pub keys4: u8,
+}
+// See MOUSE_DESC_LEN in hid.rs
+pub const NKRO_DESC_LEN: usize = 75;
+
+/// Modifier byte followed by the bitmap for keycodes 0x00-0x77
+pub const NKRO_REPORT_LEN: usize = 16;
+/// Highest keycode that can be reported
+pub const NKRO_MAX_KEYCODE: u8 = 0x77;
+const BOOT_REPORT_LEN: usize = 8;
+
+pub struct NkroKeyboard {
+    interface: u16,
+    // 0 = boot protocol, 1 = report protocol
+    protocol: AtomicU8,
+    // Written by the application
+    state: [AtomicU8; NKRO_REPORT_LEN],
+    // Copy of the report currently being sent, so all packets of it are
+    // consistent
+    sending: [AtomicU8; NKRO_REPORT_LEN],
+}
+
+impl NkroKeyboard {
+    pub const fn new(interface: u16) -> Self {
+        Self {
+            interface,
+            protocol: AtomicU8::new(1),
+            state: [const { AtomicU8::new(0) }; NKRO_REPORT_LEN],
+            sending: [const { AtomicU8::new(0) }; NKRO_REPORT_LEN],
+        }
+    }
+
+    /// Call on a bus reset, the host expects the report protocol afterwards
+    pub fn reset(&self) {
+        self.protocol.store(1, Ordering::Relaxed);
+    }
+
+    pub fn boot_protocol(&self) -> bool {
+        self.protocol.load(Ordering::Relaxed) == 0
+    }
+
+    pub fn set_modifiers(&self, modifiers: u8) {
+        self.state[0].store(modifiers, Ordering::Relaxed);
+    }
+
+    pub fn press(&self, keycode: u8) {
+        if keycode <= NKRO_MAX_KEYCODE {
+            let byte = &self.state[1 + (keycode >> 3) as usize];
+            byte.store(byte.load(Ordering::Relaxed) | 1 << (keycode & 7), Ordering::Relaxed);
+        }
+    }
+
+    pub fn release(&self, keycode: u8) {
+        if keycode <= NKRO_MAX_KEYCODE {
+            let byte = &self.state[1 + (keycode >> 3) as usize];
+            byte.store(byte.load(Ordering::Relaxed) & !(1 << (keycode & 7)), Ordering::Relaxed);
+        }
+    }
+
+    pub fn release_all(&self) {
+        for byte in &self.state {
+            byte.store(0, Ordering::Relaxed);
+        }
+    }
+
+    /// Call from the user setup handler, handles SET_PROTOCOL and GET_PROTOCOL.
+    /// Returns true if the request was for this interface.
+    pub fn handle_setup(&self, e: &mut UsbEndpoint, request: u16, wvi: u32, w_length: u16) -> bool {
+        let interface = (wvi >> 16) as u16;
+        if interface != self.interface {
+            return false;
+        }
+        if request == 0x0b21 {
+            // SET_PROTOCOL
+            self.protocol.store((wvi & 1) as u8, Ordering::Relaxed);
+            true
+        } else if request == 0x03a1 {
+            // GET_PROTOCOL
+            e.set_in_data(self.protocol.as_ptr(), (w_length as u32).min(1));
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Call from the user IN handler for the keyboard endpoint
+    pub fn handle_in<const USB_BASE: usize, const DP: u8, const DM: u8, const EPS: usize>(
+        &self,
+        e: *mut UsbEndpoint,
+        usbif: &mut UsbIf<USB_BASE, DP, DM, EPS>,
+        sendtok: u32,
+    ) {
+        let e = unsafe { &mut *e };
+        if self.boot_protocol() {
+            let mut report = [0u8; BOOT_REPORT_LEN];
+            self.boot_report(&mut report);
+            e.set_count(0);
+            unsafe { usbif.usb_send_data(report.as_ptr(), BOOT_REPORT_LEN as u32, 0, sendtok) };
+            return;
+        }
+
+        // The count is incremented when the host acknowledges a packet
+        const PACKETS: u32 = (NKRO_REPORT_LEN / 8) as u32;
+        if e.count() >= PACKETS {
+            e.set_count(0);
+        }
+        if e.count() == 0 {
+            for (sending, state) in self.sending.iter().zip(&self.state) {
+                sending.store(state.load(Ordering::Relaxed), Ordering::Relaxed);
+            }
+        }
+        let offset = e.count() as usize * 8;
+        let packet = self.sending[offset].as_ptr() as *const u8;
+        unsafe { usbif.usb_send_data(packet, 8, 0, sendtok) };
+    }
+
+    /// Converts the current state to the 6KRO boot report
+    fn boot_report(&self, report: &mut [u8; BOOT_REPORT_LEN]) {
+        report[0] = self.state[0].load(Ordering::Relaxed);
+        let mut pressed = 0;
+        // Keycodes 0-3 are reserved / error codes
+        for keycode in 4..=NKRO_MAX_KEYCODE {
+            let byte = self.state[1 + (keycode >> 3) as usize].load(Ordering::Relaxed);
+            if byte & (1 << (keycode & 7)) == 0 {
+                continue;
+            }
+            if pressed == 6 {
+                // Too many keys, report ErrorRollOver
+                report[2..].fill(0x01);
+                return;
+            }
+            report[2 + pressed] = keycode;
+            pressed += 1;
+        }
+    }
+}
@@ -0,0 +1,59 @@
+// USB pins from the ch32-hal pin types
+//
+// The assembly needs the GPIO base and the D+/D- pin numbers as constants, so
+// they are associated constants of the pin types instead of the runtime
+// `Pin::pin()` and `Pin::port()`. D+ and D- have to be on the same port, which
+// is checked with the `Port` type.
+use ch32_hal::gpio::{Input, Level, Output, Pull, Speed};
+use ch32_hal::peripherals;
+
+pub use ch32_hal::gpio::Pin;
+
+pub struct PortA;
+pub struct PortC;
+pub struct PortD;
+
+/// Pin which can be used for D+ or D-
+pub trait UsbPin: Pin {
+    type Port;
+    const GPIO_BASE: usize;
+    const PIN: u8;
+}
+
+macro_rules! usb_pins {
+    ($port_type:ident, $base:expr, [$($pin:ident: $n:expr),*]) => {
+        $(
+            impl UsbPin for peripherals::$pin {
+                type Port = $port_type;
+                const GPIO_BASE: usize = $base;
+                const PIN: u8 = $n;
+            }
+        )*
+    };
+}
+
+usb_pins!(PortA, 0x4001_0800, [PA1: 1, PA2: 2]);
+usb_pins!(PortC, 0x4001_1000, [PC0: 0, PC1: 1, PC2: 2, PC3: 3, PC4: 4, PC5: 5, PC6: 6, PC7: 7]);
+usb_pins!(PortD, 0x4001_1400, [PD0: 0, PD1: 1, PD2: 2, PD3: 3, PD4: 4, PD5: 5, PD6: 6, PD7: 7]);
+
+/// D+ and D- as floating inputs and the 1.5k pull-up on D- (low speed),
+/// owned by [`UsbIf`](crate::usb::UsbIf)
+pub struct UsbPins {
+    _dp: Input<'static>,
+    _dm: Input<'static>,
+    pull_up: Output<'static>,
+}
+
+impl UsbPins {
+    pub(crate) fn new(dp: impl Pin, dm: impl Pin, pull_up: impl Pin) -> Self {
+        Self {
+            _dp: Input::new(dp, Pull::None),
+            _dm: Input::new(dm, Pull::None),
+            pull_up: Output::new(pull_up, Level::Low, Speed::High),
+        }
+    }
+
+    pub(crate) fn set_pull_up(&mut self, connected: bool) {
+        self.pull_up.set_level(if connected { Level::High } else { Level::Low });
+    }
+}
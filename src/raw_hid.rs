@@ -0,0 +1,195 @@
+// Vendor defined ("raw") HID interface, usable from the host through hidraw
+// or hidapi without any drivers.
+//
+// Commands can either be sent as output reports on the interrupt OUT endpoint
+// (answered on the interrupt IN endpoint) or as feature reports through
+// control transfers (answered with the next feature report read).
+use core::cell::UnsafeCell;
+use core::sync::atomic::{AtomicBool, AtomicU8, Ordering};
+
+use usbd_hid::descriptor::generator_prelude::*;
+
+use crate::usb::{UsbEndpoint, UsbIf};
+
+pub const RAW_HID_REPORT_LEN: usize = 8;
+
+#[gen_hid_descriptor(
+    (collection = APPLICATION, usage_page = VENDOR_DEFINED_START, usage = 0x01) = {
+        (usage = 0x02,) = {
+            #[item_settings data,variable,absolute] input=input;
+        };
+        (usage = 0x03,) = {
+            #[item_settings data,variable,absolute] output=output;
+        };
+        (usage = 0x04,) = {
+            #[item_settings data,variable,absolute] feature=feature;
+        };
+    }
+)]
+#[allow(dead_code)]
+pub struct RawHidReport {
+    pub input: [u8; 8],
+    pub output: [u8; 8],
+    pub feature: [u8; 8],
+}
+// See MOUSE_DESC_LEN in hid.rs
+pub const RAW_HID_DESC_LEN: usize = 29;
+
+pub enum Response {
+    /// Don't answer this command
+    None,
+    Report([u8; RAW_HID_REPORT_LEN]),
+}
+
+pub struct RawHid {
+    interface: u16,
+    command: UnsafeCell<[u8; RAW_HID_REPORT_LEN]>,
+    command_pending: AtomicBool,
+    command_from_feature: AtomicBool,
+    response: UnsafeCell<[u8; RAW_HID_REPORT_LEN]>,
+    response_pending: AtomicBool,
+    feature: [UnsafeCell<[u8; RAW_HID_REPORT_LEN]>; 2],
+    // Index of the feature report buffer GET_REPORT sends
+    feature_front: AtomicU8,
+    feature_write: AtomicBool,
+}
+
+// The command and response buffers are handed over between the interrupt and
+// the main loop with the *_pending flags, only one side accesses them at a
+// time. The feature report is double buffered, the interrupt may be sending
+// the front buffer while poll writes the other one and swaps them.
+unsafe impl Sync for RawHid {}
+
+impl RawHid {
+    pub const fn new(interface: u16) -> Self {
+        Self {
+            interface,
+            command: UnsafeCell::new([0; RAW_HID_REPORT_LEN]),
+            command_pending: AtomicBool::new(false),
+            command_from_feature: AtomicBool::new(false),
+            response: UnsafeCell::new([0; RAW_HID_REPORT_LEN]),
+            response_pending: AtomicBool::new(false),
+            feature: [UnsafeCell::new([0; RAW_HID_REPORT_LEN]), UnsafeCell::new([0; RAW_HID_REPORT_LEN])],
+            feature_front: AtomicU8::new(0),
+            feature_write: AtomicBool::new(false),
+        }
+    }
+
+    /// Call on a bus reset, drops a partly received feature report and a
+    /// response the host didn't read. A command being processed is finished.
+    pub fn reset(&self) {
+        self.feature_write.store(false, Ordering::Relaxed);
+        self.response_pending.store(false, Ordering::Release);
+    }
+
+    /// Call from the user setup handler, returns true if the request was for
+    /// this interface.
+    pub fn handle_setup(&self, e: &mut UsbEndpoint, request: u16, wvi: u32, w_length: u16) -> bool {
+        let report_type = (wvi >> 8) & 0xff;
+        let interface = (wvi >> 16) as u16;
+        if interface != self.interface || report_type != 3 {
+            return false;
+        }
+        let len = (w_length as u32).min(RAW_HID_REPORT_LEN as u32);
+        if request == 0x01a1 {
+            // GET_REPORT (Feature)
+            let front = self.feature_front.load(Ordering::Acquire) as usize;
+            e.set_in_data(self.feature[front].get() as *const u8, len);
+            true
+        } else if request == 0x0921 {
+            // SET_REPORT (Feature), data follows on endpoint 0
+            e.set_max_len(len);
+            self.feature_write.store(true, Ordering::Relaxed);
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Call from the user data handler for data on endpoint 0
+    pub fn handle_feature_data(&self, e: &mut UsbEndpoint, data: &[u8]) {
+        if !self.feature_write.load(Ordering::Relaxed) {
+            return;
+        }
+        let offset = (e.count() << 3) as usize;
+        let max_len = e.max_len() as usize;
+        if offset >= max_len {
+            return;
+        }
+        let len = data.len().min(max_len - offset);
+        // Collected in the command buffer, main doesn't touch it until
+        // command_pending is set
+        if !self.command_pending.load(Ordering::Acquire) {
+            let command = unsafe { &mut *self.command.get() };
+            command[offset..offset + len].copy_from_slice(&data[..len]);
+        }
+        e.set_count(e.count() + 1);
+        if offset + len >= max_len {
+            self.feature_write.store(false, Ordering::Relaxed);
+            if !self.command_pending.load(Ordering::Acquire) {
+                let command = unsafe { &mut *self.command.get() };
+                command[max_len..].fill(0);
+                self.command_from_feature.store(true, Ordering::Relaxed);
+                self.command_pending.store(true, Ordering::Release);
+            }
+        }
+    }
+
+    /// Call from the user data handler for data on the interrupt OUT endpoint.
+    /// Commands arriving while the last one is still being processed are
+    /// dropped.
+    pub fn handle_out(&self, data: &[u8]) {
+        if self.command_pending.load(Ordering::Acquire) {
+            return;
+        }
+        let command = unsafe { &mut *self.command.get() };
+        let len = data.len().min(RAW_HID_REPORT_LEN);
+        command[..len].copy_from_slice(&data[..len]);
+        command[len..].fill(0);
+        self.command_from_feature.store(false, Ordering::Relaxed);
+        self.command_pending.store(true, Ordering::Release);
+    }
+
+    /// Call from the user IN handler for the interrupt IN endpoint
+    pub fn handle_in<const USB_BASE: usize, const DP: u8, const DM: u8, const EPS: usize>(
+        &self,
+        usbif: &mut UsbIf<USB_BASE, DP, DM, EPS>,
+        sendtok: u32,
+    ) {
+        if self.response_pending.load(Ordering::Acquire) {
+            let response = self.response.get() as *const u8;
+            unsafe { usbif.usb_send_data(response, RAW_HID_REPORT_LEN as u32, 0, sendtok) };
+            self.response_pending.store(false, Ordering::Release);
+        } else {
+            usbif.usb_send_nak();
+        }
+    }
+
+    /// Processes a pending command, call this regularly from the main loop.
+    ///
+    /// The response is sent back the way the command arrived, either as
+    /// input report or as the next feature report (the command itself
+    /// without response).
+    pub fn poll(&self, handle: impl FnOnce(&[u8]) -> Response) {
+        if !self.command_pending.load(Ordering::Acquire)
+            || self.response_pending.load(Ordering::Acquire)
+        {
+            return;
+        }
+        let command = unsafe { *self.command.get() };
+        let response = handle(&command);
+        if self.command_from_feature.load(Ordering::Relaxed) {
+            let report = match response {
+                Response::Report(report) => report,
+                Response::None => command,
+            };
+            let back = 1 - self.feature_front.load(Ordering::Relaxed) as usize;
+            unsafe { *self.feature[back].get() = report };
+            self.feature_front.store(back as u8, Ordering::Release);
+        } else if let Response::Report(report) = response {
+            unsafe { *self.response.get() = report };
+            self.response_pending.store(true, Ordering::Release);
+        }
+        self.command_pending.store(false, Ordering::Release);
+    }
+}
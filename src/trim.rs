@@ -0,0 +1,206 @@
+// HSI trimming from the 1 ms keepalives
+//
+// Without a crystal the HSI is trimmed so a frame (SE0 keepalive to SE0
+// keepalive) takes clock::CYCLES_PER_FRAME cycles. This is a PI controller on
+// the deviation of each frame: frames further off than the outlier limit
+// (missed keepalives, interrupts blocked while programming flash, suspend) are
+// ignored, the integrator is clamped to what the integral term can use and the
+// trim to the 5 bits of HSITRIM. It doesn't touch the hardware.
+
+/// Center of HSITRIM, the reset value
+pub const TRIM_CENTER: u8 = 16;
+pub const TRIM_MAX: u8 = 31;
+
+#[derive(Clone, Copy)]
+pub struct TrimConfig {
+    /// Proportional gain
+    pub kp: i32,
+    /// Integral gain
+    pub ki: i32,
+    /// Both terms are divided by 2^shift
+    pub shift: u8,
+    /// Frames off by more cycles than this are outliers
+    pub outlier_limit: i32,
+}
+
+impl TrimConfig {
+    /// The integrator of rv003usb (>> 9) with a little proportional gain. One
+    /// trim step is roughly 120 cycles per frame.
+    pub const DEFAULT: Self = Self {
+        kp: 2,
+        ki: 1,
+        shift: 9,
+        outlier_limit: 4000,
+    };
+}
+
+pub struct TrimController {
+    config: TrimConfig,
+    windup: i32,
+    windup_limit: i32,
+    trim: u8,
+    rejected: u32,
+}
+
+impl TrimController {
+    pub const fn new(config: TrimConfig) -> Self {
+        // Beyond this the integral term alone is past the end of the trim range
+        let windup_limit = if config.ki > 0 {
+            ((TRIM_CENTER as i32 + 1) << config.shift) / config.ki
+        } else {
+            0
+        };
+        Self {
+            config,
+            windup: 0,
+            windup_limit,
+            trim: TRIM_CENTER,
+            rejected: 0,
+        }
+    }
+
+    /// Continues from a known good trim (e.g. a stored one), the integrator
+    /// is set so it alone gives this trim
+    pub fn preset(&mut self, trim: u8) {
+        let offset = trim.min(TRIM_MAX) as i32 - TRIM_CENTER as i32;
+        self.windup = if self.config.ki > 0 {
+            ((-offset) << self.config.shift) / self.config.ki
+        } else {
+            0
+        };
+        self.trim = trim.min(TRIM_MAX);
+    }
+
+    /// Checks the deviation of a frame from the nominal length in cycles,
+    /// returns false (and counts it) for an outlier
+    pub fn measure(&mut self, deviance: i32) -> bool {
+        let limit = self.config.outlier_limit;
+        if deviance < limit && deviance >= -limit {
+            true
+        } else {
+            self.rejected = self.rejected.wrapping_add(1);
+            false
+        }
+    }
+
+    /// Controller step for a frame accepted by [`measure`](Self::measure),
+    /// returns the new HSITRIM value
+    pub fn update(&mut self, deviance: i32) -> u8 {
+        let TrimConfig { kp, ki, shift, .. } = self.config;
+        self.windup = (self.windup + deviance).clamp(-self.windup_limit, self.windup_limit);
+        // A long frame means the clock is fast, so the trim goes down
+        let offset = -(kp * deviance + ki * self.windup) >> shift;
+        self.trim = (TRIM_CENTER as i32 + offset).clamp(0, TRIM_MAX as i32) as u8;
+        self.trim
+    }
+
+    pub fn trim(&self) -> u8 {
+        self.trim
+    }
+
+    pub fn windup(&self) -> i32 {
+        self.windup
+    }
+
+    /// Number of frames rejected as outliers
+    pub fn rejected(&self) -> u32 {
+        self.rejected
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CYCLES_PER_FRAME: i32 = 48_000;
+    // Cycles per frame one HSITRIM step changes, see TrimConfig::DEFAULT
+    const CYCLES_PER_STEP: i32 = 120;
+
+    // Frame length deviation measured with the HSI off by drift_ppm at the
+    // given trim, plus a missed keepalive (two frames) every missed_every
+    fn simulate(controller: &mut TrimController, drift_ppm: i32, frames: u32, missed_every: u32) -> i32 {
+        let mut deviance = 0;
+        for frame in 1..=frames {
+            let trim_offset = (controller.trim() as i32 - TRIM_CENTER as i32) * CYCLES_PER_STEP;
+            deviance = CYCLES_PER_FRAME * drift_ppm / 1_000_000 + trim_offset;
+            let measured = if missed_every != 0 && frame % missed_every == 0 {
+                CYCLES_PER_FRAME + deviance
+            } else {
+                deviance
+            };
+            if controller.measure(measured) {
+                controller.update(measured);
+            }
+        }
+        deviance
+    }
+
+    #[test]
+    fn converges_under_drift() {
+        for drift_ppm in [-20_000, -5_000, 0, 7_500, 20_000] {
+            let mut controller = TrimController::new(TrimConfig::DEFAULT);
+            let deviance = simulate(&mut controller, drift_ppm, 2000, 0);
+            // Within a trim step, far inside the 1.5% low speed tolerance
+            assert!(deviance.abs() <= CYCLES_PER_STEP, "drift {drift_ppm}: {deviance}");
+            assert_eq!(controller.rejected(), 0);
+        }
+    }
+
+    #[test]
+    fn rejects_missed_keepalives() {
+        let mut controller = TrimController::new(TrimConfig::DEFAULT);
+        let deviance = simulate(&mut controller, 10_000, 2000, 50);
+        assert!(deviance.abs() <= CYCLES_PER_STEP, "{deviance}");
+        assert_eq!(controller.rejected(), 40);
+    }
+
+    #[test]
+    fn outlier_limit() {
+        let mut controller = TrimController::new(TrimConfig::DEFAULT);
+        assert!(controller.measure(3999));
+        assert!(controller.measure(-4000));
+        assert!(!controller.measure(4000));
+        assert!(!controller.measure(-4001));
+        assert!(!controller.measure(CYCLES_PER_FRAME));
+        assert_eq!(controller.rejected(), 3);
+        // Rejected frames don't touch the controller
+        assert_eq!(controller.windup(), 0);
+        assert_eq!(controller.trim(), TRIM_CENTER);
+    }
+
+    #[test]
+    fn windup_is_clamped() {
+        let config = TrimConfig::DEFAULT;
+        let limit = ((TRIM_CENTER as i32 + 1) << config.shift) / config.ki;
+        let mut controller = TrimController::new(config);
+        for _ in 0..1000 {
+            controller.update(3999);
+            assert!(controller.windup() <= limit);
+        }
+        assert_eq!(controller.windup(), limit);
+        assert_eq!(controller.trim(), 0);
+        // Unwinds as soon as the error changes sign instead of after the
+        // accumulated error is paid back
+        let mut frames = 0;
+        while controller.trim() == 0 {
+            controller.update(-100);
+            frames += 1;
+        }
+        assert!(frames < 100, "{frames}");
+    }
+
+    #[test]
+    fn trim_stays_in_range() {
+        let mut controller = TrimController::new(TrimConfig { kp: 8, ki: 4, shift: 7, outlier_limit: 4000 });
+        let mut seed = 1u32;
+        for _ in 0..10_000 {
+            seed = seed.wrapping_mul(1_103_515_245).wrapping_add(12345);
+            let deviance = (seed >> 16) as i32 % 8000 - 4000;
+            let trim = controller.update(deviance);
+            assert!(trim <= TRIM_MAX);
+            assert_eq!(trim, controller.trim());
+        }
+        controller.preset(200);
+        assert_eq!(controller.trim(), TRIM_MAX);
+    }
+}
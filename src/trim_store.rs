@@ -0,0 +1,32 @@
+// Calibrated HSI trim in the last flash page
+//
+// Trimming starts from the stored value instead of the reset value 16, so the
+// clock is close before the pull-up is enabled. UsbIf::start restores it,
+// UsbHandle::store_trim writes it once the frame timing is within tolerance.
+use crate::flash::{self, TRIM_PAGE_OFFSET};
+use crate::trim::TRIM_MAX;
+
+const MAGIC: u32 = 0x4d49_5254; // "TRIM"
+
+/// The stored trim, None if the page is erased or invalid
+pub fn load() -> Option<u8> {
+    let page = flash::read_page(TRIM_PAGE_OFFSET);
+    let magic = u32::from_le_bytes([page[0], page[1], page[2], page[3]]);
+    let (trim, check) = (page[4], page[5]);
+    if magic != MAGIC || trim != !check || trim > TRIM_MAX {
+        return None;
+    }
+    Some(trim)
+}
+
+/// Stores trim unless it's already stored
+pub fn store(trim: u8) -> Result<(), flash::Error> {
+    if load() == Some(trim) {
+        return Ok(());
+    }
+    let mut data = [0; 6];
+    data[0..4].copy_from_slice(&MAGIC.to_le_bytes());
+    data[4] = trim;
+    data[5] = !trim;
+    flash::write_page(TRIM_PAGE_OFFSET, &data)
+}
@@ -0,0 +1,464 @@
+// Types text through a keyboard interface
+//
+// Characters are queued from the main loop with `type_str` and turned into
+// press / release reports in the keyboard IN handler, so one report is sent
+// per poll of the host.
+use core::sync::atomic::{AtomicBool, AtomicU8, Ordering};
+
+/// Modifier bits in the boot keyboard report
+pub const MOD_LSHIFT: u8 = 0x02;
+pub const MOD_RALT: u8 = 0x40;
+
+const KEY_ENTER: u8 = 0x28;
+const KEY_BACKSPACE: u8 = 0x2A;
+const KEY_TAB: u8 = 0x2B;
+const KEY_SPACE: u8 = 0x2C;
+
+const QUEUE_LEN: usize = 32;
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum Layout {
+    Us,
+    /// German QWERTZ
+    De,
+    /// French AZERTY
+    Fr,
+}
+
+// Table entries: keycode, with shift / AltGr / dead key (needs a space
+// afterwards to produce the character itself) flags.
+const S: u16 = 0x100;
+const A: u16 = 0x200;
+const D: u16 = 0x400;
+
+static US: [u16; 95] = [
+    0x2C, // space
+    S | 0x1E, // !
+    S | 0x34, // "
+    S | 0x20, // #
+    S | 0x21, // $
+    S | 0x22, // %
+    S | 0x24, // &
+    0x34, // '
+    S | 0x26, // (
+    S | 0x27, // )
+    S | 0x25, // *
+    S | 0x2E, // +
+    0x36, // ,
+    0x2D, // -
+    0x37, // .
+    0x38, // /
+    0x27, // 0
+    0x1E, // 1
+    0x1F, // 2
+    0x20, // 3
+    0x21, // 4
+    0x22, // 5
+    0x23, // 6
+    0x24, // 7
+    0x25, // 8
+    0x26, // 9
+    S | 0x33, // :
+    0x33, // ;
+    S | 0x36, // <
+    0x2E, // =
+    S | 0x37, // >
+    S | 0x38, // ?
+    S | 0x1F, // @
+    S | 0x04, // A
+    S | 0x05, // B
+    S | 0x06, // C
+    S | 0x07, // D
+    S | 0x08, // E
+    S | 0x09, // F
+    S | 0x0A, // G
+    S | 0x0B, // H
+    S | 0x0C, // I
+    S | 0x0D, // J
+    S | 0x0E, // K
+    S | 0x0F, // L
+    S | 0x10, // M
+    S | 0x11, // N
+    S | 0x12, // O
+    S | 0x13, // P
+    S | 0x14, // Q
+    S | 0x15, // R
+    S | 0x16, // S
+    S | 0x17, // T
+    S | 0x18, // U
+    S | 0x19, // V
+    S | 0x1A, // W
+    S | 0x1B, // X
+    S | 0x1C, // Y
+    S | 0x1D, // Z
+    0x2F, // [
+    0x31, // \
+    0x30, // ]
+    S | 0x23, // ^
+    S | 0x2D, // _
+    0x35, // `
+    0x04, // a
+    0x05, // b
+    0x06, // c
+    0x07, // d
+    0x08, // e
+    0x09, // f
+    0x0A, // g
+    0x0B, // h
+    0x0C, // i
+    0x0D, // j
+    0x0E, // k
+    0x0F, // l
+    0x10, // m
+    0x11, // n
+    0x12, // o
+    0x13, // p
+    0x14, // q
+    0x15, // r
+    0x16, // s
+    0x17, // t
+    0x18, // u
+    0x19, // v
+    0x1A, // w
+    0x1B, // x
+    0x1C, // y
+    0x1D, // z
+    S | 0x2F, // {
+    S | 0x31, // |
+    S | 0x30, // }
+    S | 0x35, // ~
+];
+
+static DE: [u16; 95] = [
+    0x2C, // space
+    S | 0x1E, // !
+    S | 0x1F, // "
+    0x32, // #
+    S | 0x21, // $
+    S | 0x22, // %
+    S | 0x23, // &
+    S | 0x32, // '
+    S | 0x25, // (
+    S | 0x26, // )
+    S | 0x30, // *
+    0x30, // +
+    0x36, // ,
+    0x38, // -
+    0x37, // .
+    S | 0x24, // /
+    0x27, // 0
+    0x1E, // 1
+    0x1F, // 2
+    0x20, // 3
+    0x21, // 4
+    0x22, // 5
+    0x23, // 6
+    0x24, // 7
+    0x25, // 8
+    0x26, // 9
+    S | 0x37, // :
+    S | 0x36, // ;
+    0x64, // <
+    S | 0x27, // =
+    S | 0x64, // >
+    S | 0x2D, // ?
+    A | 0x14, // @
+    S | 0x04, // A
+    S | 0x05, // B
+    S | 0x06, // C
+    S | 0x07, // D
+    S | 0x08, // E
+    S | 0x09, // F
+    S | 0x0A, // G
+    S | 0x0B, // H
+    S | 0x0C, // I
+    S | 0x0D, // J
+    S | 0x0E, // K
+    S | 0x0F, // L
+    S | 0x10, // M
+    S | 0x11, // N
+    S | 0x12, // O
+    S | 0x13, // P
+    S | 0x14, // Q
+    S | 0x15, // R
+    S | 0x16, // S
+    S | 0x17, // T
+    S | 0x18, // U
+    S | 0x19, // V
+    S | 0x1A, // W
+    S | 0x1B, // X
+    S | 0x1D, // Y
+    S | 0x1C, // Z
+    A | 0x25, // [
+    A | 0x2D, // \
+    A | 0x26, // ]
+    D | 0x35, // ^
+    S | 0x38, // _
+    S | D | 0x2E, // `
+    0x04, // a
+    0x05, // b
+    0x06, // c
+    0x07, // d
+    0x08, // e
+    0x09, // f
+    0x0A, // g
+    0x0B, // h
+    0x0C, // i
+    0x0D, // j
+    0x0E, // k
+    0x0F, // l
+    0x10, // m
+    0x11, // n
+    0x12, // o
+    0x13, // p
+    0x14, // q
+    0x15, // r
+    0x16, // s
+    0x17, // t
+    0x18, // u
+    0x19, // v
+    0x1A, // w
+    0x1B, // x
+    0x1D, // y
+    0x1C, // z
+    A | 0x24, // {
+    A | 0x64, // |
+    A | 0x27, // }
+    A | 0x30, // ~
+];
+
+static FR: [u16; 95] = [
+    0x2C, // space
+    0x38, // !
+    0x20, // "
+    A | 0x20, // #
+    0x30, // $
+    S | 0x34, // %
+    0x1E, // &
+    0x21, // '
+    0x22, // (
+    0x2D, // )
+    0x32, // *
+    S | 0x2E, // +
+    0x10, // ,
+    0x23, // -
+    S | 0x36, // .
+    S | 0x37, // /
+    S | 0x27, // 0
+    S | 0x1E, // 1
+    S | 0x1F, // 2
+    S | 0x20, // 3
+    S | 0x21, // 4
+    S | 0x22, // 5
+    S | 0x23, // 6
+    S | 0x24, // 7
+    S | 0x25, // 8
+    S | 0x26, // 9
+    0x37, // :
+    0x36, // ;
+    0x64, // <
+    0x2E, // =
+    S | 0x64, // >
+    S | 0x10, // ?
+    A | 0x27, // @
+    S | 0x14, // A
+    S | 0x05, // B
+    S | 0x06, // C
+    S | 0x07, // D
+    S | 0x08, // E
+    S | 0x09, // F
+    S | 0x0A, // G
+    S | 0x0B, // H
+    S | 0x0C, // I
+    S | 0x0D, // J
+    S | 0x0E, // K
+    S | 0x0F, // L
+    S | 0x33, // M
+    S | 0x11, // N
+    S | 0x12, // O
+    S | 0x13, // P
+    S | 0x04, // Q
+    S | 0x15, // R
+    S | 0x16, // S
+    S | 0x17, // T
+    S | 0x18, // U
+    S | 0x19, // V
+    S | 0x1D, // W
+    S | 0x1B, // X
+    S | 0x1C, // Y
+    S | 0x1A, // Z
+    A | 0x22, // [
+    A | 0x25, // \
+    A | 0x2D, // ]
+    A | 0x26, // ^
+    0x25, // _
+    A | D | 0x24, // `
+    0x14, // a
+    0x05, // b
+    0x06, // c
+    0x07, // d
+    0x08, // e
+    0x09, // f
+    0x0A, // g
+    0x0B, // h
+    0x0C, // i
+    0x0D, // j
+    0x0E, // k
+    0x0F, // l
+    0x33, // m
+    0x11, // n
+    0x12, // o
+    0x13, // p
+    0x04, // q
+    0x15, // r
+    0x16, // s
+    0x17, // t
+    0x18, // u
+    0x19, // v
+    0x1D, // w
+    0x1B, // x
+    0x1C, // y
+    0x1A, // z
+    A | 0x21, // {
+    A | 0x23, // |
+    A | 0x2E, // }
+    A | D | 0x1F, // ~
+];
+
+/// A single key press
+#[derive(Clone, Copy)]
+pub struct Keystroke {
+    pub modifier: u8,
+    pub keycode: u8,
+    /// The key is a dead key on this layout, press space afterwards
+    pub dead: bool,
+}
+
+/// Maps an ASCII character to the key producing it, returns None for
+/// characters that can't be typed.
+pub fn ascii_to_key(c: u8, layout: Layout) -> Option<Keystroke> {
+    let entry = match c {
+        b'\n' => KEY_ENTER as u16,
+        b'\t' => KEY_TAB as u16,
+        0x08 => KEY_BACKSPACE as u16,
+        0x20..=0x7e => {
+            let table = match layout {
+                Layout::Us => &US,
+                Layout::De => &DE,
+                Layout::Fr => &FR,
+            };
+            table[(c - 0x20) as usize]
+        }
+        _ => return None,
+    };
+    let mut modifier = 0;
+    if entry & S != 0 {
+        modifier |= MOD_LSHIFT;
+    }
+    if entry & A != 0 {
+        modifier |= MOD_RALT;
+    }
+    Some(Keystroke {
+        modifier,
+        keycode: entry as u8,
+        dead: entry & D != 0,
+    })
+}
+
+pub struct Typist {
+    layout: AtomicU8,
+    queue: [AtomicU8; QUEUE_LEN],
+    // Written by type_str
+    head: AtomicU8,
+    // Written by next_report
+    tail: AtomicU8,
+    release_pending: AtomicBool,
+    space_pending: AtomicBool,
+}
+
+impl Typist {
+    pub const fn new(layout: Layout) -> Self {
+        Self {
+            layout: AtomicU8::new(layout as u8),
+            queue: [const { AtomicU8::new(0) }; QUEUE_LEN],
+            head: AtomicU8::new(0),
+            tail: AtomicU8::new(0),
+            release_pending: AtomicBool::new(false),
+            space_pending: AtomicBool::new(false),
+        }
+    }
+
+    pub fn set_layout(&self, layout: Layout) {
+        self.layout.store(layout as u8, Ordering::Relaxed);
+    }
+
+    fn layout(&self) -> Layout {
+        match self.layout.load(Ordering::Relaxed) {
+            1 => Layout::De,
+            2 => Layout::Fr,
+            _ => Layout::Us,
+        }
+    }
+
+    /// True while there are characters left to type
+    pub fn busy(&self) -> bool {
+        self.head.load(Ordering::Acquire) != self.tail.load(Ordering::Acquire)
+            || self.release_pending.load(Ordering::Relaxed)
+            || self.space_pending.load(Ordering::Relaxed)
+    }
+
+    /// Queues a string for typing. Returns the number of bytes queued, which
+    /// is less than the length of the string if the queue is full. Characters
+    /// the layout can't produce are skipped.
+    pub fn type_str(&self, s: &str) -> usize {
+        let mut head = self.head.load(Ordering::Relaxed);
+        for (queued, c) in s.bytes().enumerate() {
+            let next = (head + 1) % QUEUE_LEN as u8;
+            if next == self.tail.load(Ordering::Acquire) {
+                return queued;
+            }
+            self.queue[head as usize].store(c, Ordering::Relaxed);
+            head = next;
+            self.head.store(head, Ordering::Release);
+        }
+        s.len()
+    }
+
+    /// Call from the keyboard IN handler before sending the report. Fills in
+    /// the next boot keyboard report and returns true while typing, returns
+    /// false and leaves the report untouched otherwise.
+    pub fn next_report(&self, report: &mut [u8; 8]) -> bool {
+        if self.release_pending.load(Ordering::Relaxed) {
+            self.release_pending.store(false, Ordering::Relaxed);
+            report.fill(0);
+            return true;
+        }
+        if self.space_pending.load(Ordering::Relaxed) {
+            self.space_pending.store(false, Ordering::Relaxed);
+            Self::press(report, 0, KEY_SPACE);
+            self.release_pending.store(true, Ordering::Relaxed);
+            return true;
+        }
+        let head = self.head.load(Ordering::Acquire);
+        let mut tail = self.tail.load(Ordering::Relaxed);
+        while tail != head {
+            let c = self.queue[tail as usize].load(Ordering::Relaxed);
+            tail = (tail + 1) % QUEUE_LEN as u8;
+            self.tail.store(tail, Ordering::Release);
+            if let Some(key) = ascii_to_key(c, self.layout()) {
+                Self::press(report, key.modifier, key.keycode);
+                self.release_pending.store(true, Ordering::Relaxed);
+                self.space_pending.store(key.dead, Ordering::Relaxed);
+                return true;
+            }
+        }
+        false
+    }
+
+    fn press(report: &mut [u8; 8], modifier: u8, keycode: u8) {
+        report.fill(0);
+        report[0] = modifier;
+        report[2] = keycode;
+    }
+}
@@ -20,12 +20,25 @@
 // LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
 // OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
 // SOFTWARE.
-use ch32_hal::pac::{FLASH, PFIC, RCC, SYSTICK};
+use crate::clock;
+#[cfg(feature = "se0-keepalive-trim")]
+use crate::clock::ClockSource;
+use crate::trim::{TrimConfig, TrimController};
+use crate::pins::{Pin, UsbPin, UsbPins};
+use ch32_hal::pac::{self, AFIO, EXTI, FLASH, PFIC, PWR, RCC, SYSTICK};
 use core::arch::asm;
+use core::cell::UnsafeCell;
 use core::hint::unreachable_unchecked;
-use core::mem;
+use core::mem::{self, MaybeUninit};
+use core::sync::atomic::{AtomicBool, AtomicI32, AtomicU32, AtomicU8, Ordering};
 
-const ENDPOINT0_SIZE: u32 = 8;
+// pac::EXTI isn't usable in const operands of the interrupt handler
+const EXTI_BASE: usize = 0x4001_0400;
+const EXTI_INTFR: usize = EXTI_BASE + 0x14;
+const GPIOA_BASE: usize = 0x4001_0800;
+
+/// Max packet size of endpoint 0
+pub const ENDPOINT0_SIZE: u32 = 8;
 
 pub struct UsbEndpoint {
     count: u32,
@@ -50,6 +63,500 @@ impl UsbEndpoint {
             opaque: core::ptr::null(),
         }
     }
+
+    /// Number of packets already transferred in the current transfer
+    pub fn count(&self) -> u32 {
+        self.count
+    }
+
+    pub fn set_count(&mut self, count: u32) {
+        self.count = count;
+    }
+
+    /// Length of the current control transfer
+    pub fn max_len(&self) -> u32 {
+        self.max_len
+    }
+
+    pub fn set_max_len(&mut self, max_len: u32) {
+        self.max_len = max_len;
+    }
+
+    /// Sets the data which will be sent in response to IN tokens during the
+    /// data stage of a control transfer. The data has to outlive the transfer.
+    pub fn set_in_data(&mut self, data: *const u8, length: u32) {
+        self.opaque = data;
+        self.max_len = length;
+    }
+
+    /// Claims the current control transfer, call from the setup handler.
+    /// The IN tokens of its data and status stage are then passed to the user
+    /// IN handler as endpoint 0 instead of being answered from
+    /// [`set_in_data`](Self::set_in_data), so long reads can be generated
+    /// packet by packet ([`count`](Self::count) is the packet index, at most
+    /// [`ENDPOINT0_SIZE`] bytes each) and the status stage of a write can be
+    /// NAKed until it's done. The claim ends with the next SETUP.
+    #[cfg(feature = "custom-ep0")]
+    pub fn claim(&mut self) {
+        self.custom = 1;
+    }
+
+    #[cfg(feature = "custom-ep0")]
+    pub fn is_claimed(&self) -> bool {
+        self.custom != 0
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum EndpointType {
+    Control,
+    Interrupt,
+}
+
+/// Entry of the endpoint table passed to [`UsbIf::new`], index n is
+/// endpoint n. The stack STALLs tokens for directions an endpoint doesn't
+/// have and packets longer than its max packet size.
+#[derive(Clone, Copy)]
+pub struct EndpointConfig {
+    pub kind: EndpointType,
+    pub has_in: bool,
+    pub has_out: bool,
+    pub max_packet_size: u8,
+    /// bInterval in ms, interrupt endpoints only
+    pub interval: u8,
+}
+
+impl EndpointConfig {
+    /// Endpoint 0
+    pub const CONTROL: Self = Self {
+        kind: EndpointType::Control,
+        has_in: true,
+        has_out: true,
+        max_packet_size: ENDPOINT0_SIZE as u8,
+        interval: 0,
+    };
+
+    /// Endpoint number without IN or OUT endpoint
+    pub const UNUSED: Self = Self {
+        kind: EndpointType::Interrupt,
+        has_in: false,
+        has_out: false,
+        max_packet_size: 0,
+        interval: 0,
+    };
+
+    /// Low speed only allows interrupt endpoints of up to 8 bytes besides
+    /// endpoint 0.
+    pub const fn interrupt(has_in: bool, has_out: bool, max_packet_size: u8, interval: u8) -> Self {
+        assert!(max_packet_size <= 8);
+        Self {
+            kind: EndpointType::Interrupt,
+            has_in,
+            has_out,
+            max_packet_size,
+            interval,
+        }
+    }
+
+    /// Endpoint descriptor, address is the endpoint number with bit 7 set for
+    /// the IN direction
+    pub const fn descriptor(&self, address: u8) -> [u8; 7] {
+        [
+            7,    // bLength
+            0x05, // bDescriptorType (Endpoint)
+            address,
+            match self.kind {
+                EndpointType::Control => 0x00,
+                EndpointType::Interrupt => 0x03,
+            }, // Attributes
+            self.max_packet_size,
+            0x00, // wMaxPacketSize
+            self.interval,
+        ]
+    }
+}
+
+/// Bus state changes passed to the handler set with
+/// [`UsbIf::set_event_handler`], called from the interrupt
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum UsbEvent {
+    /// The host reset the bus, the device is back in the Default state
+    /// (address 0) and the host enumerates it again
+    Reset,
+    /// No keepalive for 3 ms, the device may draw at most 2.5 mA, see
+    /// [`UsbHandle::sleep`]. Reported by [`UsbHandle::poll`] instead of the
+    /// interrupt.
+    Suspended,
+    /// Bus activity after Suspended
+    Resumed,
+}
+
+/// What causes a reboot, see [`UsbIf::set_reboot_trigger`]
+#[cfg(feature = "reboot")]
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum RebootTrigger {
+    /// SET_REPORT of this feature report id (interface 0) with the rv003usb
+    /// magic as data, 0xfd by default
+    #[cfg(feature = "reboot-feature-report")]
+    FeatureReport(u8),
+    /// Vendor request with this bRequest and no data stage
+    VendorRequest(u8),
+    /// DFU_DETACH to this interface, see [`crate::dfu`]
+    DfuDetach(u16),
+    None,
+}
+
+/// Where a reboot goes, see [`UsbIf::set_reboot_target`]
+#[cfg(feature = "reboot")]
+#[derive(Clone, Copy)]
+pub enum RebootTarget {
+    /// Factory bootloader in the boot area
+    SystemBootloader,
+    /// Plain reset into user flash
+    Reset,
+    /// Anything else, e.g. [`crate::dfu::reboot_to_dfu_bootloader`]
+    Custom(fn() -> !),
+}
+
+/// Returned by the reboot hook, see [`UsbIf::set_reboot_hook`]
+#[cfg(feature = "reboot")]
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum RebootAction {
+    /// Reboot after the status stage of the request
+    Proceed,
+    /// Complete the request and reboot from the next [`UsbHandle::poll`]
+    /// instead, so the main loop can finish its work (e.g. a flash write)
+    /// before calling it
+    Defer,
+    /// Ignore the request
+    Veto,
+}
+
+#[cfg(feature = "reboot")]
+pub fn reboot(target: RebootTarget) -> ! {
+    match target {
+        RebootTarget::SystemBootloader => reboot_to_bootloader(),
+        RebootTarget::Reset => system_reset(),
+        RebootTarget::Custom(reboot) => reboot(),
+    }
+}
+
+/// Reboots into the bootloader (boot mode flag) with a system reset
+pub fn reboot_to_bootloader() -> ! {
+    FLASH.boot_modekeyp().write(|w| w.set_modekeyr(0x45670123)); // FLASH_KEY1
+    FLASH.boot_modekeyp().write(|w| w.set_modekeyr(0xCDEF89AB)); // FLASH_KEY2
+    FLASH.statr().write(|w| w.set_boot_mode(true)); // 1<<14 is zero, so, boot bootloader code. Unset for user code.
+
+    FLASH.ctlr().write(|w| w.set_lock(true));
+    RCC.rstsckr().modify(|w| w.set_rmvf(true));
+    system_reset();
+}
+
+/// Resets the chip, which starts the code in user flash unless the boot mode
+/// flag is set
+pub fn system_reset() -> ! {
+    PFIC.sctlr().write(|w| w.set_sysreset(true));
+    unsafe { unreachable_unchecked() };
+}
+
+#[cfg(feature = "reboot")]
+fn reboot_now(_target: RebootTarget) -> RebootAction {
+    RebootAction::Proceed
+}
+
+fn no_event(_event: UsbEvent) {}
+
+#[cfg(feature = "user-out-data")]
+fn no_user_data(_e: &mut UsbEndpoint, _endp: u32, _data: &[u8]) {}
+
+#[cfg(feature = "user-setup")]
+fn no_user_setup(_e: &mut UsbEndpoint, _request: u16, _wvi: u32, _w_length: u16) {}
+
+/// Static home of the USB state, shared with the interrupt handler generated
+/// by [`usb_interrupt!`](crate::usb_interrupt)
+pub struct UsbCell<T> {
+    usb: UnsafeCell<MaybeUninit<T>>,
+    initialized: AtomicBool,
+    shared: UsbShared,
+}
+
+unsafe impl<T> Sync for UsbCell<T> {}
+
+impl<const USB_BASE: usize, const DP: u8, const DM: u8, const EPS: usize>
+    UsbCell<UsbIf<USB_BASE, DP, DM, EPS>>
+{
+    pub const fn new() -> Self {
+        Self {
+            usb: UnsafeCell::new(MaybeUninit::uninit()),
+            initialized: AtomicBool::new(false),
+            shared: UsbShared::new(),
+        }
+    }
+
+    /// Moves the USB state to its final address and returns it for the
+    /// configuration, [`UsbIf::start`] enables the interrupt. Call once from
+    /// main, panics if called again.
+    pub fn init(&'static self, mut usb: UsbIf<USB_BASE, DP, DM, EPS>) -> &'static mut UsbIf<USB_BASE, DP, DM, EPS> {
+        assert!(!self.initialized.load(Ordering::Relaxed));
+        self.initialized.store(true, Ordering::Relaxed);
+        usb.shared = &self.shared;
+        unsafe { (*self.usb.get()).write(usb) }
+    }
+
+    // The body of the generated EXTI7_0_IRQHandler. The interrupt is shared
+    // by EXTI lines 0-7 (and may already be enabled by ch32-hal), it leaves
+    // the state alone until UsbIf::start gave up main's reference.
+    #[doc(hidden)]
+    #[inline(always)]
+    pub fn handle_interrupt(&'static self) {
+        if self.shared.ready.load(Ordering::Acquire) {
+            unsafe { (*(*self.usb.get()).as_mut_ptr()).usb_interrupt_handler() };
+        }
+    }
+}
+
+// The part of the USB state main reads after UsbIf::start. It's outside of
+// UsbIf (the interrupt handler has it mutably borrowed the whole time) and
+// atomics only.
+struct UsbShared {
+    // Set at the end of UsbIf::start, the interrupt handler owns the UsbIf
+    // from then on
+    ready: AtomicBool,
+    // SysTick at the last keepalive, valid once active is set
+    last_keepalive: AtomicU32,
+    active: AtomicBool,
+    suspended: AtomicBool,
+    // Set on the status stage of a reboot request the hook deferred
+    #[cfg(feature = "reboot")]
+    reboot_pending: AtomicBool,
+    // Of the last frame, in cycles, i32::MAX before the first one
+    frame_deviance: AtomicI32,
+    trim: AtomicU8,
+    windup: AtomicI32,
+    rejected: AtomicU32,
+}
+
+// Of a UsbIf until UsbCell::init moves it into its cell
+static UNATTACHED: UsbShared = UsbShared::new();
+
+impl UsbShared {
+    const fn new() -> Self {
+        Self {
+            ready: AtomicBool::new(false),
+            last_keepalive: AtomicU32::new(0),
+            active: AtomicBool::new(false),
+            suspended: AtomicBool::new(false),
+            #[cfg(feature = "reboot")]
+            reboot_pending: AtomicBool::new(false),
+            frame_deviance: AtomicI32::new(i32::MAX),
+            trim: AtomicU8::new(0),
+            windup: AtomicI32::new(0),
+            rejected: AtomicU32::new(0),
+        }
+    }
+
+    fn publish_trim(&self, trim: &TrimController) {
+        self.trim.store(trim.trim(), Ordering::Relaxed);
+        self.windup.store(trim.windup(), Ordering::Relaxed);
+        self.rejected.store(trim.rejected(), Ordering::Relaxed);
+    }
+
+    fn frame_timing_ok(&self) -> bool {
+        const TOLERANCE: i32 = clock::CYCLES_PER_FRAME as i32 * 15 / 1000;
+        let deviance = self.frame_deviance.load(Ordering::Relaxed);
+        deviance <= TOLERANCE && deviance >= -TOLERANCE
+    }
+
+    fn timing_stats(&self) -> TimingStats {
+        let deviance = self.frame_deviance.load(Ordering::Relaxed);
+        TimingStats {
+            frame_period: if deviance == i32::MAX {
+                0
+            } else {
+                (clock::CYCLES_PER_FRAME as i32 + deviance) as u32
+            },
+            trim: self.trim.load(Ordering::Relaxed),
+            windup: self.windup.load(Ordering::Relaxed),
+            rejected: self.rejected.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Defines `EXTI7_0_IRQHandler` for the USB state in a [`UsbCell`] static,
+/// `usb_composite!` does this for its module.
+#[macro_export]
+macro_rules! usb_interrupt {
+    ($cell:path) => {
+        const _: () = {
+            use $crate::__private::interrupt;
+
+            #[interrupt]
+            fn EXTI7_0_IRQHandler() {
+                // IMPORTANT: Keep latency low here
+                $cell.handle_interrupt();
+            }
+        };
+    };
+}
+
+/// Frame timing and HSI trimming, see [`UsbIf::timing_stats`]
+#[derive(Clone, Copy)]
+pub struct TimingStats {
+    /// SysTick cycles between the last two keepalives which weren't
+    /// outliers, 0 before the first one
+    pub frame_period: u32,
+    /// HSITRIM value
+    pub trim: u8,
+    /// Integrator of the trim controller
+    pub windup: i32,
+    /// Frames rejected as outliers
+    pub rejected: u32,
+}
+
+/// The part of the USB state main keeps after [`UsbIf::start`]
+pub struct UsbHandle<const USB_BASE: usize, const DP: u8, const DM: u8, const EPS: usize> {
+    pins: Option<UsbPins>,
+    // The rest of UsbIf is owned by the interrupt handler
+    shared: &'static UsbShared,
+    event_handler: fn(UsbEvent),
+    #[cfg(feature = "reboot")]
+    reboot_target: RebootTarget,
+}
+
+impl<const USB_BASE: usize, const DP: u8, const DM: u8, const EPS: usize>
+    UsbHandle<USB_BASE, DP, DM, EPS>
+{
+    /// See [`UsbIf::frame_timing_ok`]
+    pub fn frame_timing_ok(&self) -> bool {
+        self.shared.frame_timing_ok()
+    }
+
+    /// See [`UsbIf::timing_stats`]
+    pub fn timing_stats(&self) -> TimingStats {
+        self.shared.timing_stats()
+    }
+
+    /// Stores the current HSI trim for the next start if the frame timing is
+    /// within tolerance, returns whether it's stored. Programming flash blocks
+    /// the USB interrupt for a few ms, the host would see missed packets and
+    /// may reset the device. Call it while the bus is suspended
+    /// ([`poll`](Self::poll) returned true, before [`sleep`](Self::sleep)) or
+    /// disconnected, and not again every loop.
+    #[cfg(feature = "persist-trim")]
+    pub fn store_trim(&self) -> Result<bool, crate::flash::Error> {
+        if !self.frame_timing_ok() {
+            return Ok(false);
+        }
+        crate::trim_store::store(self.timing_stats().trim)?;
+        Ok(true)
+    }
+
+    /// Enables the pull-up, the host starts enumerating
+    pub fn connect(&mut self) {
+        if let Some(pins) = &mut self.pins {
+            pins.set_pull_up(true);
+        }
+    }
+
+    /// Disables the pull-up, the host sees a disconnect
+    pub fn disconnect(&mut self) {
+        if let Some(pins) = &mut self.pins {
+            pins.set_pull_up(false);
+        }
+    }
+
+    /// Detects suspend, call from the main loop at least every ms. Returns
+    /// true while the bus has been idle for 3 ms (after the first keepalive)
+    /// and reports [`UsbEvent::Suspended`] to the event handler the first
+    /// time. Reboots if the reboot hook returned [`RebootAction::Defer`].
+    pub fn poll(&self) -> bool {
+        const SUSPEND_CYCLES: u32 = clock::CYCLES_PER_FRAME * 3;
+
+        #[cfg(feature = "reboot")]
+        if self.shared.reboot_pending.load(Ordering::Acquire) {
+            reboot(self.reboot_target);
+        }
+
+        if !self.shared.active.load(Ordering::Acquire) {
+            return false;
+        }
+        let last_keepalive = self.shared.last_keepalive.load(Ordering::Relaxed);
+        if !Self::bus_idle() || SYSTICK.cnt().read().wrapping_sub(last_keepalive) <= SUSPEND_CYCLES {
+            return false;
+        }
+        if !self.shared.suspended.load(Ordering::Relaxed) {
+            self.shared.suspended.store(true, Ordering::Relaxed);
+            (self.event_handler)(UsbEvent::Suspended);
+        }
+        true
+    }
+
+    // Idle is J (D- high for low speed), a bus reset (SE0) or the resume
+    // signalling (K) isn't suspend
+    fn bus_idle() -> bool {
+        const INDR: usize = 0x08;
+        let indr = unsafe { ((USB_BASE + INDR) as *const u32).read_volatile() };
+        indr & ((1 << DP) | (1 << DM)) == 1 << DM
+    }
+
+    /// Enters Standby until the host resumes the bus, for
+    /// [`UsbEvent::Suspended`]. Returns right away if the bus isn't suspended
+    /// (anymore). The D- line wakes the device through its EXTI event instead
+    /// of the interrupt, the interrupt needs the 48 MHz clock which is
+    /// restored (together with the HSI trim) before it's enabled again.
+    /// [`UsbEvent::Resumed`] follows with the next keepalive.
+    pub fn sleep(&self) {
+        let line = DM as usize;
+
+        EXTI.intenr().modify(|w| w.set_mr(line, false));
+        EXTI.evenr().modify(|w| w.set_mr(line, true));
+        // Checked with the interrupt masked: a resume the interrupt saw before
+        // cleared suspended, one starting after it is K on the bus (for 20 ms)
+        // or, once past this check, a pending wakeup event for the wfi
+        if self.shared.suspended.load(Ordering::Relaxed) && Self::bus_idle() {
+            self.standby();
+        }
+        EXTI.evenr().modify(|w| w.set_mr(line, false));
+        EXTI.intfr().write(|w| w.set_if_(line, true));
+        EXTI.intenr().modify(|w| w.set_mr(line, true));
+    }
+
+    fn standby(&self) {
+        let ctlr = RCC.ctlr().read();
+        let cfgr0 = RCC.cfgr0().read();
+
+        RCC.apb1pcenr().modify(|w| w.set_pwren(true));
+        PWR.ctlr().modify(|w| w.set_pdds(true));
+        PFIC.sctlr().modify(|w| {
+            w.set_sleepdeep(true);
+            w.set_wfitowfe(true);
+        });
+        unsafe { asm!("wfi") };
+        PFIC.sctlr().modify(|w| {
+            w.set_sleepdeep(false);
+            w.set_wfitowfe(false);
+        });
+
+        // Standby falls back to the HSI without PLL
+        if ctlr.hseon() {
+            RCC.ctlr().modify(|w| w.set_hseon(true));
+            while !RCC.ctlr().read().hserdy() {}
+        }
+        // PLLSRC can only change while running from the HSI with the PLL off
+        RCC.cfgr0().write_value(pac::rcc::regs::Cfgr0(cfgr0.0 & !0b11));
+        RCC.ctlr().modify(|w| w.set_pllon(true));
+        while !RCC.ctlr().read().pllrdy() {}
+        RCC.cfgr0().write_value(cfgr0);
+        #[cfg(feature = "se0-keepalive-trim")]
+        if clock::SOURCE == ClockSource::Hsi {
+            let trim = self.shared.trim.load(Ordering::Relaxed);
+            RCC.ctlr().modify(|w| w.set_hsitrim(trim));
+        }
+
+        // The resume signalling isn't a packet. If the bus stays idle poll()
+        // reports suspend again after 3 ms.
+        self.shared.last_keepalive.store(SYSTICK.cnt().read(), Ordering::Relaxed);
+    }
 }
 
 #[repr(C, packed)]
@@ -63,52 +570,326 @@ pub struct UsbIf<const USB_BASE: usize, const DP: u8, const DM: u8, const EPS: u
     current_endpoint: u32,
     my_address: u32,
     setup_request: u32,
+    #[cfg(feature = "reboot")]
     reboot_armed: u32,
+    #[cfg(feature = "reboot")]
+    reboot_trigger: RebootTrigger,
+    #[cfg(feature = "reboot")]
+    reboot_target: RebootTarget,
+    #[cfg(feature = "reboot")]
+    reboot_hook: fn(RebootTarget) -> RebootAction,
     last_se0_cyccount: u32,
-    se0_windup: i32,
+    trim: TrimController,
     usb_handle_user_in_request: fn(*mut UsbEndpoint, *mut u8, i32, u32, &mut Self),
+    #[cfg(feature = "user-out-data")]
+    usb_handle_user_data: fn(&mut UsbEndpoint, u32, &[u8]),
+    #[cfg(feature = "user-setup")]
+    usb_handle_user_setup: fn(&mut UsbEndpoint, u16, u32, u16),
     get_descriptor_info: fn(u32) -> (*const u8, u16),
+    event_handler: fn(UsbEvent),
+    shared: &'static UsbShared,
     eps: [UsbEndpoint; EPS], // ENDPOINTS
+    endpoints: [EndpointConfig; EPS],
+    pins: Option<UsbPins>,
 }
 
 impl<const USB_BASE: usize, const DP: u8, const DM: u8, const EPS: usize>
     UsbIf<USB_BASE, DP, DM, EPS>
 {
+    /// Endpoint 0 of the table has to be [`EndpointConfig::CONTROL`].
     pub fn new(
+        endpoints: [EndpointConfig; EPS],
         usb_handle_user_in_request: fn(*mut UsbEndpoint, *mut u8, i32, u32, &mut Self),
         get_descriptor_info: fn(u32) -> (*const u8, u16),
     ) -> Self {
+        assert!(endpoints[0].kind == EndpointType::Control);
         Self {
             current_endpoint: 0,
             my_address: 0,
             setup_request: 0,
+            #[cfg(feature = "reboot")]
             reboot_armed: 0,
+            #[cfg(feature = "reboot-feature-report")]
+            reboot_trigger: RebootTrigger::FeatureReport(0xfd),
+            #[cfg(all(feature = "reboot", not(feature = "reboot-feature-report")))]
+            reboot_trigger: RebootTrigger::None,
+            #[cfg(feature = "reboot")]
+            reboot_target: RebootTarget::SystemBootloader,
+            #[cfg(feature = "reboot")]
+            reboot_hook: reboot_now,
             last_se0_cyccount: 0,
-            se0_windup: 0,
+            trim: TrimController::new(TrimConfig::DEFAULT),
             usb_handle_user_in_request,
+            #[cfg(feature = "user-out-data")]
+            usb_handle_user_data: no_user_data,
+            #[cfg(feature = "user-setup")]
+            usb_handle_user_setup: no_user_setup,
             get_descriptor_info,
+            event_handler: no_event,
+            shared: &UNATTACHED,
             eps: [const { UsbEndpoint::new() }; EPS],
+            endpoints,
+            pins: None,
         }
     }
 
-    // This logic is mostly equivalent to the rv003usb assembler code
-    unsafe extern "C" fn handle_se0_keepalive(&mut self) {
-        const TARGET_CYCLES: i32 = 48000; // 48 MHz
-        const LIMIT: i32 = 4000;
+    /// Like [`new`](Self::new), but takes the D+, D- and pull-up pins. The pin
+    /// types have to match USB_BASE, DP and DM (checked at compile time) and
+    /// D+ and D- have to be on the same port. The pull-up stays off until
+    /// [`UsbHandle::connect`].
+    pub fn with_pins<P: UsbPin, M: UsbPin<Port = P::Port>>(
+        dp: P,
+        dm: M,
+        pull_up: impl Pin,
+        endpoints: [EndpointConfig; EPS],
+        usb_handle_user_in_request: fn(*mut UsbEndpoint, *mut u8, i32, u32, &mut Self),
+        get_descriptor_info: fn(u32) -> (*const u8, u16),
+    ) -> Self {
+        const {
+            assert!(P::GPIO_BASE == USB_BASE && P::PIN == DP && M::PIN == DM);
+        }
+        let mut usb = Self::new(endpoints, usb_handle_user_in_request, get_descriptor_info);
+        usb.pins = Some(UsbPins::new(dp, dm, pull_up));
+        usb
+    }
+
+    /// Routes the EXTI line of D- (the line the interrupt handler
+    /// acknowledges) to falling edges of D- and enables the EXTI7_0 interrupt
+    /// at the highest priority, the packet timing doesn't allow it to be
+    /// preempted. Restores the stored HSI trim (feature persist-trim). Takes
+    /// the state from [`UsbCell::init`] for good, the returned handle connects
+    /// to the host.
+    pub fn start(&'static mut self) -> UsbHandle<USB_BASE, DP, DM, EPS> {
+        const { assert!(USB_BASE >= GPIOA_BASE && DM < 8) };
+        debug_assert!(EXTI.as_ptr() as usize == EXTI_BASE);
+        let line = DM as usize;
+        let port = ((USB_BASE - GPIOA_BASE) / 0x400) as u8;
+
+        RCC.apb2pcenr().modify(|w| w.set_afioen(true));
+        AFIO.exticr().modify(|w| w.set_exti(line, port));
+        //Warning: The interrupts need the 48MHz SYSCLK of clock::rcc_config()
+        EXTI.ftenr().modify(|w| w.set_tr(line, true));
+        EXTI.rtenr().modify(|w| w.set_tr(line, false));
+        EXTI.intfr().write(|w| w.set_if_(line, true));
+        EXTI.intenr().modify(|w| w.set_mr(line, true)); // enable interrupt
+
+        // Start from the stored trim, the host sees the clock only after the
+        // pull-up is enabled
+        #[cfg(feature = "persist-trim")]
+        if clock::SOURCE == ClockSource::Hsi {
+            if let Some(trim) = crate::trim_store::load() {
+                self.trim.preset(trim);
+                RCC.ctlr().modify(|w| w.set_hsitrim(trim));
+            }
+        }
+        self.shared.publish_trim(&self.trim);
+
+        let irq = pac::Interrupt::EXTI7_0 as u8;
+        unsafe {
+            qingke::pfic::set_priority(irq, 0);
+            qingke::pfic::enable_interrupt(irq);
+        }
+        let handle = UsbHandle {
+            pins: self.pins.take(),
+            shared: self.shared,
+            event_handler: self.event_handler,
+            #[cfg(feature = "reboot")]
+            reboot_target: self.reboot_target,
+        };
+        // The interrupt handler takes over, self isn't used anymore
+        handle.shared.ready.store(true, Ordering::Release);
+        handle
+    }
+
+    /// Handler for data received with OUT transfers, called with the endpoint
+    /// number and the payload (without PID and CRC).
+    ///
+    /// Endpoint 0 data is only passed on if it's not part of a setup packet,
+    /// i.e. the data stage of a control write. If the setup handler set a
+    /// length with [`UsbEndpoint::set_max_len`], the handler has to advance
+    /// [`UsbEndpoint::count`] by one for every packet of the data stage.
+    /// Otherwise the status stage sends the remaining bytes instead of a zero
+    /// length packet.
+    #[cfg(feature = "user-out-data")]
+    pub fn set_user_data_handler(&mut self, handler: fn(&mut UsbEndpoint, u32, &[u8])) {
+        self.usb_handle_user_data = handler;
+    }
+
+    /// Handler for control requests the stack doesn't handle itself, called
+    /// with wRequestType | bRequest << 8, wValue | wIndex << 16 and wLength.
+    ///
+    /// For control reads, set the response with [`UsbEndpoint::set_in_data`],
+    /// for control writes set the expected length with
+    /// [`UsbEndpoint::set_max_len`] and receive the data through the user data
+    /// handler, which has to count the packets (see
+    /// [`set_user_data_handler`](Self::set_user_data_handler)). Without
+    /// max_len the status stage is a zero length packet right away.
+    #[cfg(feature = "user-setup")]
+    pub fn set_user_setup_handler(&mut self, handler: fn(&mut UsbEndpoint, u16, u32, u16)) {
+        self.usb_handle_user_setup = handler;
+    }
+
+    /// Called from the interrupt on bus resets and resume, from
+    /// [`UsbHandle::poll`] on suspend. The stack only resets its own state on
+    /// a bus reset, the handler resets the class state (`usb_composite!` sets
+    /// its own handler for this).
+    pub fn set_event_handler(&mut self, handler: fn(UsbEvent)) {
+        self.event_handler = handler;
+    }
+
+    /// Request which reboots the device after its status stage, the rv003usb
+    /// feature report by default (if enabled)
+    #[cfg(feature = "reboot")]
+    pub fn set_reboot_trigger(&mut self, trigger: RebootTrigger) {
+        self.reboot_trigger = trigger;
+    }
+
+    /// Where the reboot goes, the factory bootloader by default
+    #[cfg(feature = "reboot")]
+    pub fn set_reboot_target(&mut self, target: RebootTarget) {
+        self.reboot_target = target;
+    }
+
+    /// Called from the interrupt when the reboot request was received, e.g. to
+    /// defer the reboot until pending data is written to flash
+    #[cfg(feature = "reboot")]
+    pub fn set_reboot_hook(&mut self, hook: fn(RebootTarget) -> RebootAction) {
+        self.reboot_hook = hook;
+    }
+
+    /// Gains and outlier limit of the HSI trimming (the outlier limit also
+    /// applies to the frame timing), resets the controller
+    pub fn set_trim_config(&mut self, config: TrimConfig) {
+        self.trim = TrimController::new(config);
+    }
+
+    /// Whether the last frame measured from the keepalives was within the
+    /// ±1.5% low speed clock tolerance, false before the first one. Measured
+    /// with and without trimming (feature se0-keepalive-trim), from the HSI or
+    /// a crystal.
+    pub fn frame_timing_ok(&self) -> bool {
+        self.shared.frame_timing_ok()
+    }
+
+    /// Measured frame period and state of the HSI trimming. The trim is only
+    /// updated with feature se0-keepalive-trim and when running from the HSI.
+    pub fn timing_stats(&self) -> TimingStats {
+        self.shared.timing_stats()
+    }
+
+    // Takes the trigger instead of self, it's called while an endpoint is borrowed
+    #[cfg(feature = "reboot")]
+    fn is_reboot_request(trigger: RebootTrigger, request: u16, wvi: u32) -> bool {
+        // We shift down because we don't care if USB_RECIP_INTERFACE is set or not.
+        match trigger {
+            #[cfg(feature = "reboot-feature-report")]
+            RebootTrigger::FeatureReport(id) => {
+                // Class request (Will be writing)  This is hid_send_feature_report
+                request >> 1 == 0x0921 >> 1 && wvi == 0x0300 | id as u32
+            }
+            RebootTrigger::VendorRequest(b_request) => {
+                request >> 1 == ((b_request as u16) << 8 | 0x40) >> 1
+            }
+            RebootTrigger::DfuDetach(interface) => {
+                request == 0x0021 && wvi >> 16 == interface as u32
+            }
+            RebootTrigger::None => false,
+        }
+    }
+
+    #[cfg(feature = "reboot")]
+    fn start_reboot(&mut self) {
+        #[cfg(feature = "reboot-feature-report")]
+        if let RebootTrigger::FeatureReport(_) = self.reboot_trigger {
+            // The magic follows in the data stage
+            self.reboot_armed = 1;
+            return;
+        }
+        // No data stage, reboot on the status stage IN
+        self.arm_reboot();
+    }
+
+    /// Reboots on the next IN (the status stage) unless the hook objects, a
+    /// deferred reboot is left to UsbHandle::poll from there
+    #[cfg(feature = "reboot")]
+    fn arm_reboot(&mut self) {
+        self.reboot_armed = match (self.reboot_hook)(self.reboot_target) {
+            RebootAction::Proceed => 2,
+            RebootAction::Defer => 3,
+            RebootAction::Veto => 0,
+        };
+    }
+
+    pub fn usb_send_nak(&mut self) {
+        unsafe { self.usb_send_data(core::ptr::null(), 0, 2, 0x5A) };
+    }
+
+    pub fn usb_send_stall(&mut self) {
+        unsafe { self.usb_send_data(core::ptr::null(), 0, 2, 0x1E) };
+    }
+
+    // Called by the interrupt handler when it starts with SE0. Keepalives
+    // are 2 bit times (1.33 µs), SE0 longer than 2.5 µs is a bus reset.
+    unsafe extern "C" fn handle_se0(&mut self) {
+        const RESET_CYCLES: u32 = clock::SYSCLK_HZ / 400_000;
+        const INDR: usize = 0x08;
+
+        let start = SYSTICK.cnt().read();
+        let indr = (USB_BASE + INDR) as *const u32;
+        while indr.read_volatile() & ((1 << DP) | (1 << DM)) == 0 {
+            if SYSTICK.cnt().read().wrapping_sub(start) > RESET_CYCLES {
+                self.bus_reset();
+                return;
+            }
+        }
+        self.shared.last_keepalive.store(start, Ordering::Relaxed);
+        self.shared.active.store(true, Ordering::Release);
+        if self.shared.suspended.load(Ordering::Relaxed) {
+            self.shared.suspended.store(false, Ordering::Relaxed);
+            (self.event_handler)(UsbEvent::Resumed);
+        }
+        self.handle_se0_keepalive();
+    }
+
+    fn bus_reset(&mut self) {
+        self.my_address = 0;
+        self.setup_request = 0;
+        self.current_endpoint = 0;
+        self.shared.suspended.store(false, Ordering::Relaxed);
+        #[cfg(feature = "reboot")]
+        {
+            self.reboot_armed = 0;
+        }
+        for e in self.eps.iter_mut() {
+            *e = UsbEndpoint::new();
+        }
+        (self.event_handler)(UsbEvent::Reset);
+    }
+
+    // Measures the frame since the last keepalive, see trim.rs for the trimming
+    fn handle_se0_keepalive(&mut self) {
+        const TARGET_CYCLES: i32 = clock::CYCLES_PER_FRAME as i32;
 
         let systick_cnt = SYSTICK.cnt().read();
         let delta_se0_cyccount = systick_cnt.wrapping_sub(self.last_se0_cyccount);
         self.last_se0_cyccount = systick_cnt;
         let deviance = delta_se0_cyccount as i32 - TARGET_CYCLES;
 
-        // If outside our limit
-        if deviance < LIMIT || deviance >= -LIMIT {
-            self.se0_windup += deviance;
-            let hsi_trim_offset = (-self.se0_windup) >> 9;
-            // 16 is the default value of the trim
-            let hsi_trim = hsi_trim_offset + 16;
-
-            RCC.ctlr().modify(|w| w.set_hsitrim(hsi_trim as u8));
+        // Outliers are missed keepalives or interrupts which were blocked
+        // (e.g. while programming flash)
+        if !self.trim.measure(deviance) {
+            self.shared.publish_trim(&self.trim);
+            return;
+        }
+        self.shared.frame_deviance.store(deviance, Ordering::Relaxed);
+        // A crystal (clock::SOURCE, chosen at build time like the clock
+        // configuration) doesn't need trimming, and changing the HSI trim
+        // while not running from it only does harm
+        #[cfg(feature = "se0-keepalive-trim")]
+        if clock::SOURCE == ClockSource::Hsi {
+            let hsi_trim = self.trim.update(deviance);
+            RCC.ctlr().modify(|w| w.set_hsitrim(hsi_trim));
+            self.shared.publish_trim(&self.trim);
         }
     }
 
@@ -116,6 +897,17 @@ impl<const USB_BASE: usize, const DP: u8, const DM: u8, const EPS: usize>
         unsafe { self.usb_send_data(&[0_u8, 0_u8] as *const u8, 2, 2, token) };
     }
 
+    /// Sends a packet, for user IN handlers a data packet (`poly_function`
+    /// 0) answering the IN token with `token` (`sendtok`).
+    ///
+    /// # Safety
+    ///
+    /// `data` has to be valid for `length` bytes, which for data packets
+    /// must not exceed the max packet size of the endpoint
+    /// ([`EndpointConfig`], at most 8 bytes for low speed). It isn't checked
+    /// here, this runs right before the bus turnaround.
+    // The delays here and in the interrupt handler are clock::CYCLES_PER_BIT
+    // (32) cycles per bit, counted for execution from flash with 1 wait state
     #[inline(never)]
     pub unsafe fn usb_send_data(
         &mut self,
@@ -451,7 +1243,7 @@ impl<const USB_BASE: usize, const DP: u8, const DM: u8, const EPS: usize>
 
         "la ra, ret_from_se0", // Common return address for all function calls.
         // Finish jump to se0
-        "c.beqz a4, {handle_se0_keepalive}",
+        "c.beqz a4, {handle_se0}",
 
         "c.lw a0, {INDR_OFFSET}(a5); c.andi a0, {USB_DMASK}; bne a0, a1, syncout",
         "c.lw a0, {INDR_OFFSET}(a5); c.andi a0, {USB_DMASK}; bne a0, a1, syncout",
@@ -791,7 +1583,7 @@ impl<const USB_BASE: usize, const DP: u8, const DM: u8, const EPS: usize>
         // EXTI->INTFR = 1<<4
         "c.j 1f; 1:", // Extra little bit of delay to make sure we don't accidentally false fire.
 
-        "la a5, {EXTI_BASE} + 20",
+        "la a5, {EXTI_INTFR}",
         "li a0, (1<<{USB_PIN_DM})",
         "sw a0, 0(a5)",
 
@@ -813,20 +1605,22 @@ impl<const USB_BASE: usize, const DP: u8, const DM: u8, const EPS: usize>
             USB_DMASK = const ((1<<(DP)) | 1<<(DM)),
             USB_BUFFER_SIZE = const 12, // Packet Type + 8 + CRC + Buffer
             ENDPOINTS = const EPS,
-            // NOTE the following is *not* compile time defined
-            // EXTI_BASE = const (EXTI.as_ptr()) as u32,
-            EXTI_BASE = const 0x40010400,
+            EXTI_INTFR = const EXTI_INTFR,
             usb_pid_handle_data = sym Self::usb_pid_handle_data,
             usb_pid_handle_in = sym Self::usb_pid_handle_in,
             usb_pid_handle_out = sym Self::usb_pid_handle_out,
             usb_pid_handle_ack = sym Self::usb_pid_handle_ack,
             usb_pid_handle_setup = sym Self::usb_pid_handle_setup,
-            handle_se0_keepalive = sym Self::handle_se0_keepalive,
+            handle_se0 = sym Self::handle_se0,
         );
     }
 
     extern "C" fn usb_pid_handle_in(&mut self, _addr: u32, data: *mut u8, endp: u32) {
         self.current_endpoint = endp;
+        if !self.endpoints[endp as usize].has_in {
+            self.usb_send_stall();
+            return;
+        }
 
         let e = &mut self.eps[endp as usize];
         let sendtok = if e.toggle_in != 0 {
@@ -834,31 +1628,32 @@ impl<const USB_BASE: usize, const DP: u8, const DM: u8, const EPS: usize>
         } else {
             0b11000011
         };
+        #[cfg(feature = "reboot")]
         if self.reboot_armed == 2 {
             self.usb_send_empty(sendtok);
 
-            // Initiate boot into bootloader
-            FLASH.boot_modekeyp().write(|w| w.set_modekeyr(0x45670123)); // FLASH_KEY1
-            FLASH.boot_modekeyp().write(|w| w.set_modekeyr(0xCDEF89AB)); // FLASH_KEY2
-            FLASH.statr().write(|w| w.set_boot_mode(true)); // 1<<14 is zero, so, boot bootloader code. Unset for user code.
-
-            FLASH.ctlr().write(|w| w.set_lock(true));
-            RCC.rstsckr().modify(|w| w.set_rmvf(true));
-            // reset here
-            PFIC.sctlr().write(|w| w.set_sysreset(true));
-            unsafe { unreachable_unchecked() };
+            reboot(self.reboot_target);
         }
-        if (e.custom != 0) || (endp != 0) {
+        #[cfg(feature = "reboot")]
+        if self.reboot_armed == 3 {
+            self.usb_send_empty(sendtok);
+            self.reboot_armed = 0;
+            self.shared.reboot_pending.store(true, Ordering::Release);
+            return;
+        }
+        #[cfg(feature = "custom-ep0")]
+        let user = (e.custom != 0) || (endp != 0);
+        #[cfg(not(feature = "custom-ep0"))]
+        let user = endp != 0;
+        if user {
             (self.usb_handle_user_in_request)(e, data, endp as i32, sendtok, self);
             return;
         }
         let tsend = e.opaque;
         let offset = e.count << 3;
-        let tosend = if (e.max_len - offset) > ENDPOINT0_SIZE {
-            ENDPOINT0_SIZE
-        } else {
-            e.max_len - offset
-        };
+        // Nothing left (e.g. the status stage of a control write whose data
+        // handler counted a short last packet) is a zero length packet
+        let tosend = e.max_len.saturating_sub(offset).min(ENDPOINT0_SIZE);
         let sendnow = tsend.wrapping_add(offset as usize);
         if tosend <= 0 {
             self.usb_send_empty(sendtok);
@@ -876,10 +1671,24 @@ impl<const USB_BASE: usize, const DP: u8, const DM: u8, const EPS: usize>
     ) {
         let epno = self.current_endpoint;
 
-        let e = &mut self.eps[epno as usize];
-
         length -= 3;
 
+        // SETUP only to control endpoints, OUT only to endpoints with an OUT
+        // direction
+        let config = self.endpoints[epno as usize];
+        let accepted = if self.setup_request != 0 {
+            config.kind == EndpointType::Control
+        } else {
+            config.has_out
+        };
+        if !accepted || length > config.max_packet_size as u32 {
+            self.setup_request = 0;
+            self.usb_send_stall();
+            return;
+        }
+
+        let e = &mut self.eps[epno as usize];
+
         // Already received this packet.
         if e.toggle_out != which_data {
             unsafe {
@@ -889,18 +1698,33 @@ impl<const USB_BASE: usize, const DP: u8, const DM: u8, const EPS: usize>
         }
         e.toggle_out = e.toggle_out ^ 0b1;
 
-        if epno != 0 || ((self.setup_request == 0) && length > 3) {
-            if self.reboot_armed > 0 {
-                let data_u32 = data as *const u32;
-                if unsafe {
-                    epno == 0
-                        && data_u32.read_unaligned() == 0xaa3412fd
-                        && (data_u32.add(1).read_unaligned() & 0x00ffffff) == 0x00ddccbb
-                } {
-                    e.count = 7;
-                    self.reboot_armed = 2;
-                } else {
-                    self.reboot_armed = 0;
+        // Short packets at the end of a control write carry data too, only the
+        // zero length status stage is skipped.
+        if epno != 0 || ((self.setup_request == 0) && length > 0) {
+            #[cfg(feature = "reboot-feature-report")]
+            let reboot_data = self.reboot_armed > 0;
+            #[cfg(not(feature = "reboot-feature-report"))]
+            let reboot_data = false;
+            if reboot_data {
+                #[cfg(feature = "reboot-feature-report")]
+                {
+                    let data_u32 = data as *const u32;
+                    if unsafe {
+                        epno == 0
+                            && data_u32.read_unaligned() == 0xaa3412fd
+                            && (data_u32.add(1).read_unaligned() & 0x00ffffff) == 0x00ddccbb
+                    } {
+                        e.count = 7;
+                        self.arm_reboot();
+                    } else {
+                        self.reboot_armed = 0;
+                    }
+                }
+            } else {
+                #[cfg(feature = "user-out-data")]
+                {
+                    let data = unsafe { core::slice::from_raw_parts(data, length as usize) };
+                    (self.usb_handle_user_data)(e, epno, data);
                 }
             }
         } else if self.setup_request != 0 {
@@ -917,12 +1741,15 @@ impl<const USB_BASE: usize, const DP: u8, const DM: u8, const EPS: usize>
             // We shift down because we don't care if USB_RECIP_INTERFACE is set or not.
             // Otherwise we have to write extra code to handle each case if it's set or
             // not set, but in general, there's never a situation where we really care.
-            let req_shl = s.w_request_type_lsb_request_msb >> 1;
-            if req_shl == (0x0921 >> 1) {
-                // Class request (Will be writing)  This is hid_send_feature_report
-                if wvi == 0x000003fd {
-                    self.reboot_armed = 1;
-                }
+            let request = s.w_request_type_lsb_request_msb;
+            let req_shl = request >> 1;
+            #[cfg(feature = "reboot")]
+            let reboot_request = Self::is_reboot_request(self.reboot_trigger, request, wvi);
+            #[cfg(not(feature = "reboot"))]
+            let reboot_request = false;
+            if reboot_request {
+                #[cfg(feature = "reboot")]
+                self.start_reboot();
             } else if req_shl == (0x0680 >> 1) {
                 let (descriptor_addr, descriptor_len) = (self.get_descriptor_info)(wvi);
                 e.opaque = descriptor_addr;
@@ -932,6 +1759,9 @@ impl<const USB_BASE: usize, const DP: u8, const DM: u8, const EPS: usize>
             } else if req_shl == (0x0500 >> 1) {
                 // SET_ADDRESS = 0x05
                 self.my_address = wvi;
+            } else {
+                #[cfg(feature = "user-setup")]
+                (self.usb_handle_user_setup)(e, request, wvi, w_length);
             }
         }
         // Got the right data. Acknowledge.
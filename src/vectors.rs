@@ -0,0 +1,11 @@
+// External interrupt vectors with the USB handler, kept by the EXTERN in
+// link_usb.x. The table is generated by build.rs from its interrupt list, the
+// vector of the USB EXTI line is the EXTI7_0_IRQHandler from usb_interrupt!.
+use ch32_hal::pac::Interrupt;
+
+pub union Vector {
+    _handler: unsafe extern "C" fn(),
+    _reserved: u32,
+}
+
+include!(concat!(env!("OUT_DIR"), "/vectors.rs"));